@@ -1,8 +1,14 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
 
 use crate::assembler::TokenType::*;
-use crate::vm::Inst;
+use crate::diagnostic::{Diagnostic, Source, SourceFile, Span};
+use crate::image::{Image, memory_size_for, Section, SectionKind};
+use crate::object::{Object, Relocation, Symbol};
+use crate::preprocessor::preprocess;
+use crate::vm::{Inst, MAX_MEMORY_SIZE};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Token(TokenType, (usize, usize));
@@ -14,9 +20,25 @@ pub enum TokenType {
     Comma,
     NewLine,
     Colon,
+    Directive,
+    Str,
+    Op,
+    LParen,
+    RParen,
+    // Character that can't start any token
+    Error,
     Eof,
 }
 
+// Operators allowed in constant expressions, from lowest to highest precedence
+const BINARY_OPERATORS: [&[(&str, Operator)]; 5] = [
+    &[("|", Operator::Or)],
+    &[("&", Operator::And)],
+    &[("<<", Operator::ShiftLeft), (">>", Operator::ShiftRight)],
+    &[("+", Operator::Add), ("-", Operator::Sub)],
+    &[("*", Operator::Mul), ("/", Operator::Div), ("%", Operator::Mod)],
+];
+
 pub fn read_all_tokens(source: &[u8]) -> Vec<Token> {
     let mut res = Vec::new();
     let mut ptr = 0usize;
@@ -33,9 +55,13 @@ pub fn read_all_tokens(source: &[u8]) -> Vec<Token> {
                     ptr += 1;
                 }
             }
-            b' ' => {
+            b' ' | b'\t' => {
                 ptr += 1;
             }
+            b'\r' if ptr + 1 < source.len() && source[ptr + 1] == b'\n' => {
+                res.push(Token(NewLine, (ptr, ptr + 2)));
+                ptr += 2;
+            }
             b',' => {
                 res.push(Token(Comma, (ptr, ptr + 1)));
                 ptr += 1;
@@ -48,40 +74,83 @@ pub fn read_all_tokens(source: &[u8]) -> Vec<Token> {
                 res.push(Token(Colon, (ptr, ptr + 1)));
                 ptr += 1;
             }
+            b'(' => {
+                res.push(Token(LParen, (ptr, ptr + 1)));
+                ptr += 1;
+            }
+            b')' => {
+                res.push(Token(RParen, (ptr, ptr + 1)));
+                ptr += 1;
+            }
+            b'+' | b'-' | b'*' | b'/' | b'%' | b'&' | b'|' | b'~' => {
+                res.push(Token(Op, (ptr, ptr + 1)));
+                ptr += 1;
+            }
+            b'<' | b'>' if ptr + 1 < source.len() && source[ptr + 1] == source[ptr] => {
+                res.push(Token(Op, (ptr, ptr + 2)));
+                ptr += 2;
+            }
             b'0'..=b'9' => {
+                // Takes prefixes and bad digits too, the parser reports malformed numbers
                 let start = ptr;
-                let mut hex = false;
-
-                if source[ptr] == b'0'
-                    && ptr + 2 < source.len()
-                    && source[ptr + 1] == b'x'
-                    && source[ptr + 2].is_ascii_digit() {
-                    hex = true;
-                    ptr += 2;
+                while ptr < source.len() && (source[ptr].is_ascii_alphanumeric() || source[ptr] == b'_') {
+                    ptr += 1;
                 }
-
-                loop {
-                    if ptr >= source.len() { break; }
-                    if hex {
-                        if !source[ptr].is_ascii_hexdigit() { break; }
-                    } else {
-                        if !source[ptr].is_ascii_digit() { break; }
+                res.push(Token(Int, (start, ptr)));
+            }
+            b'\'' => {
+                // Character literals are integers, the parser decodes them
+                let start = ptr;
+                ptr += 1;
+                while ptr < source.len() && source[ptr] != b'\'' && source[ptr] != b'\n' {
+                    if source[ptr] == b'\\' && ptr + 1 < source.len() && source[ptr + 1] != b'\n' {
+                        ptr += 1;
                     }
                     ptr += 1;
                 }
+                if ptr < source.len() && source[ptr] == b'\'' {
+                    ptr += 1;
+                }
                 res.push(Token(Int, (start, ptr)));
             }
-            b'a'..=b'z' | b'_' => {
+            b'.' if ptr + 1 < source.len() && source[ptr + 1].is_ascii_alphabetic() => {
                 let start = ptr;
-                while let b'a'..=b'z' | b'_' = source[ptr] {
+                ptr += 1;
+                while ptr < source.len() && is_identifier_char(source[ptr]) {
+                    ptr += 1;
+                }
+                res.push(Token(Directive, (start, ptr)));
+            }
+            b'"' => {
+                // Unterminated strings stop at the end of the line, the parser reports them
+                let start = ptr;
+                ptr += 1;
+                while ptr < source.len() && source[ptr] != b'"' && source[ptr] != b'\n' {
+                    if source[ptr] == b'\\' && ptr + 1 < source.len() && source[ptr + 1] != b'\n' {
+                        ptr += 1;
+                    }
+                    ptr += 1;
+                }
+                if ptr < source.len() && source[ptr] == b'"' {
+                    ptr += 1;
+                }
+                res.push(Token(Str, (start, ptr)));
+            }
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' | b'$' => {
+                let start = ptr;
+                while ptr < source.len() && is_identifier_char(source[ptr]) {
                     ptr += 1;
-                    if ptr >= source.len() { break; }
                 }
                 res.push(Token(Id, (start, ptr)));
             }
             _ => {
-                // Ignored
+                // Keeps multi-byte UTF-8 characters in one token
+                let start = ptr;
                 ptr += 1;
+                while ptr < source.len() && (0x80..0xC0).contains(&source[ptr]) {
+                    ptr += 1;
+                }
+                res.push(Token(Error, (start, ptr)));
             }
         }
     }
@@ -89,35 +158,112 @@ pub fn read_all_tokens(source: &[u8]) -> Vec<Token> {
     res
 }
 
+fn is_identifier_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$'
+}
+
 pub fn print_tokens(source: &[u8], tokens: &Vec<Token>) {
     for Token(ty, span) in tokens {
         match ty {
             Id => print!(" Id({})", String::from_utf8_lossy(&source[span.0..span.1])),
             Int => print!(" Int({})", String::from_utf8_lossy(&source[span.0..span.1])),
             Comma => print!(","),
-            NewLine => print!("\n"),
+            NewLine => println!(),
             Colon => print!(": "),
-            Eof => print!(" EOF\n"),
+            Directive => print!(" Directive({})", String::from_utf8_lossy(&source[span.0..span.1])),
+            Str => print!(" Str({})", String::from_utf8_lossy(&source[span.0..span.1])),
+            Op => print!(" Op({})", String::from_utf8_lossy(&source[span.0..span.1])),
+            LParen => print!(" ("),
+            RParen => print!(" )"),
+            Error => print!(" Error({})", String::from_utf8_lossy(&source[span.0..span.1])),
+            Eof => println!(" EOF"),
         }
     }
 }
 
+pub fn assemble(file: &SourceFile) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    assemble_with_includes(file, &[])
+}
+
+// `include_paths` are searched for `.include`d files not found next to the including file
+pub fn assemble_with_includes(file: &SourceFile, include_paths: &[PathBuf]) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let (file, parsed) = parse_file(file, include_paths)?;
+    Compiler::new().compile(&file, &parsed)
+}
+
+// Assembles into an object for the linker instead of a program
+pub fn assemble_object(file: &SourceFile, include_paths: &[PathBuf]) -> Result<Object, Vec<Diagnostic>> {
+    let (file, parsed) = parse_file(file, include_paths)?;
+    Compiler::new().compile_object(&file, &parsed)
+}
+
+// Assembles into a loadable image with sections and symbols
+pub fn assemble_image(file: &SourceFile, include_paths: &[PathBuf]) -> Result<Image, Vec<Diagnostic>> {
+    let (file, parsed) = parse_file(file, include_paths)?;
+    Compiler::new().compile_image(&file, &parsed)
+}
+
+// Also renders a listing, see `Compiler::listing`
+pub fn assemble_listing(file: &SourceFile, include_paths: &[PathBuf]) -> Result<(Image, String), Vec<Diagnostic>> {
+    let (file, parsed) = parse_file(file, include_paths)?;
+    let mut compiler = Compiler::new();
+    let image = compiler.compile_image(&file, &parsed)?;
+    let listing = compiler.listing(&file, &image.memory());
+    Ok((image, listing))
+}
+
+// Also returns where every instruction came from, for the debugger
+pub fn assemble_debug(file: &SourceFile, include_paths: &[PathBuf]) -> Result<(Image, Vec<SourceLine>), Vec<Diagnostic>> {
+    let (file, parsed) = parse_file(file, include_paths)?;
+    let mut compiler = Compiler::new();
+    let image = compiler.compile_image(&file, &parsed)?;
+    Ok((image, compiler.line_table(&file)))
+}
+
+// Returns the preprocessed file too, statement spans point into it
+fn parse_file(file: &SourceFile, include_paths: &[PathBuf]) -> Result<(SourceFile, Vec<Statement>), Vec<Diagnostic>> {
+    let file = preprocess(file, include_paths)?;
+    let tokens = read_all_tokens(&file.text);
+    let parsed = Parser::new(&file, tokens).parse()?;
+    Ok((file, parsed))
+}
+
+// Source of the code at `address`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceLine {
+    pub address: u16,
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+}
+
 pub struct Parser<'a> {
+    file: &'a SourceFile,
     source: &'a [u8],
     tokens: Vec<Token>,
     pos: usize,
 }
 
+#[derive(Clone, Debug)]
+pub struct Statement {
+    pub inst: ParsedInst,
+    pub span: Span,
+}
+
 #[derive(Clone, Debug)]
 pub enum ParsedInst {
     Label { label: String },
     Nop,
     Exit,
     Jump { label: String },
+    // Always the absolute form
+    LongJump { label: String },
     Then,
     Otherwise,
     SetByte { dst: u32, val: u8 },
-    SetShort { dst: u32, val: u16 },
+    SetShort { dst: u32, val: Expr },
+    // Byte or short form, picked once constants are known
+    Set { dst: u32, val: Expr },
     Push { src: u32 },
     Pop { dst: u32 },
     Add { dst: u32, src: u32 },
@@ -135,150 +281,409 @@ pub enum ParsedInst {
     Return,
     Call { label: String },
     Mov { dst: u32, src: u32 },
-    Debug { src: u32, mode: u32 },
+    Debug { src: u32, mode: Expr },
+    LoadWord { dst: u32, base: u32, offset: Expr },
+    LoadByte { dst: u32, base: u32, offset: Expr },
+    StoreWord { src: u32, base: u32, offset: Expr },
+    StoreByte { src: u32, base: u32, offset: Expr },
+    And { dst: u32, src: u32 },
+    Or { dst: u32, src: u32 },
+    Xor { dst: u32, src: u32 },
+    Not { dst: u32 },
+    ShiftLeft { dst: u32, src: u32 },
+    ShiftRight { dst: u32, src: u32 },
+    ShiftRightArith { dst: u32, src: u32 },
+    Byte { values: Vec<Expr> },
+    Word { values: Vec<Expr> },
+    Zero { len: Expr },
+    Equ { name: String, value: Expr },
+    Global { names: Vec<String> },
+    Section { kind: SectionKind },
+    Entry { label: String },
+    NoExit,
+}
+
+// Immediate operand, symbols are resolved by the compiler once addresses are known
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Expr {
+    Int(i32),
+    Symbol(String),
+    Unary(Operator, Box<Expr>),
+    Binary(Operator, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Operator {
+    Neg,
+    Not,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    ShiftLeft,
+    ShiftRight,
+    And,
+    Or,
+}
+
+impl Expr {
+    pub fn has_symbols(&self) -> bool {
+        match self {
+            Expr::Int(_) => false,
+            Expr::Symbol(_) => true,
+            Expr::Unary(_, expr) => expr.has_symbols(),
+            Expr::Binary(_, left, right) => left.has_symbols() || right.has_symbols(),
+        }
+    }
+
+    // Evaluates with 32-bit signed arithmetic, reporting overflow instead of wrapping
+    pub fn eval(&self, symbol: &dyn Fn(&str) -> Result<i32, String>) -> Result<i32, String> {
+        let overflow = || "Arithmetic overflow in expression".to_string();
+
+        match self {
+            Expr::Int(value) => Ok(*value),
+            Expr::Symbol(name) => symbol(name),
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(symbol)?;
+                match op {
+                    Operator::Neg => value.checked_neg().ok_or_else(overflow),
+                    _ => Ok(!value),
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let left = left.eval(symbol)?;
+                let right = right.eval(symbol)?;
+
+                if right == 0 && matches!(op, Operator::Div | Operator::Mod) {
+                    return Err("Division by zero in expression".to_string());
+                }
+                let value = match op {
+                    Operator::Add => left.checked_add(right),
+                    Operator::Sub => left.checked_sub(right),
+                    Operator::Mul => left.checked_mul(right),
+                    Operator::Div => left.checked_div(right),
+                    Operator::Mod => left.checked_rem(right),
+                    Operator::ShiftLeft => u32::try_from(right).ok()
+                        .and_then(|shift| left.checked_shl(shift))
+                        .filter(|value| value >> right == left),
+                    Operator::ShiftRight => u32::try_from(right).ok().and_then(|shift| left.checked_shr(shift)),
+                    Operator::And => Some(left & right),
+                    Operator::Or => Some(left | right),
+                    Operator::Neg | Operator::Not => None,
+                };
+                value.ok_or_else(overflow)
+            }
+        }
+    }
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(source: &[u8], tokens: Vec<Token>) -> Parser {
+    pub fn new(file: &'a SourceFile, tokens: Vec<Token>) -> Parser<'a> {
         Parser {
-            source,
+            file,
+            source: &file.text,
             tokens,
             pos: 0,
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<ParsedInst>, String> {
-        let mut inst = Vec::new();
+    // Reports every invalid line instead of stopping at the first one
+    pub fn parse(&mut self) -> Result<Vec<Statement>, Vec<Diagnostic>> {
+        let mut statements = Vec::new();
+        let mut errors = Vec::new();
 
         loop {
-            let Token(token_type, span) = self.tk(self.pos);
-            match token_type {
-                Id => self.parse_instruction(&mut inst)?,
-                Int => return Err(format!("Unexpected token Int at {:?}", span)),
-                Comma => return Err(format!("Unexpected token Comma at {:?}", span)),
-                NewLine => { self.pos += 1; }
-                Colon => return Err(format!("Unexpected token Colon at {:?}", span)),
+            let Token(token_type, _) = *self.tk(self.pos);
+
+            // Bad characters are reported on their own instead of as parse errors
+            let invalid = self.invalid_characters();
+            if !invalid.is_empty() {
+                errors.extend(invalid);
+                self.skip_line();
+                continue;
+            }
+
+            let result = match token_type {
+                Id | Directive => self.parse_statement(),
+                NewLine => {
+                    self.pos += 1;
+                    continue;
+                }
                 Eof => break,
+                _ => Err(self.error(format!("Unexpected token {:?}", token_type))),
+            };
+
+            match result {
+                Ok(statement) => statements.push(statement),
+                Err(diagnostic) => {
+                    errors.push(diagnostic);
+                    self.skip_line();
+                }
             }
         }
 
-        Ok(inst)
+        if errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, Diagnostic> {
+        let Token(token_type, (start, _)) = *self.tk(self.pos);
+        let inst = if token_type == Directive {
+            self.parse_directive()?
+        } else {
+            self.parse_instruction()?
+        };
+        let end = self.tk(self.pos - 1).1 .1;
+
+        if self.tk(self.pos).0 != Eof {
+            self.consume(NewLine)?;
+        }
+        Ok(Statement { inst, span: (start, end) })
+    }
+
+    fn invalid_characters(&self) -> Vec<Diagnostic> {
+        self.tokens[self.pos..].iter()
+            .take_while(|Token(token_type, _)| !matches!(token_type, NewLine | Eof))
+            .filter(|Token(token_type, _)| *token_type == Error)
+            .map(|Token(_, span)| self.error_at(*span, format!("Unexpected character {:?}", self.str(*span))))
+            .collect()
     }
 
-    fn parse_instruction(&mut self, inst: &mut Vec<ParsedInst>) -> Result<(), String> {
+    fn skip_line(&mut self) {
+        while !matches!(self.tk(self.pos).0, NewLine | Eof) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_instruction(&mut self) -> Result<ParsedInst, Diagnostic> {
+        let name_span = self.tk(self.pos).1;
         let name = self.consume_id()?;
 
-        match name.as_ref() {
-            "nop" => inst.push(ParsedInst::Nop),
-            "exit" => inst.push(ParsedInst::Exit),
+        let inst = match name.as_ref() {
+            "nop" => ParsedInst::Nop,
+            "exit" => ParsedInst::Exit,
             "jmp" => {
                 let arg1 = self.consume_id()?;
-                inst.push(ParsedInst::Jump { label: arg1 });
+                ParsedInst::Jump { label: arg1 }
+            }
+            "ljmp" => {
+                let label = self.consume_id()?;
+                ParsedInst::LongJump { label }
             }
-            "then" => inst.push(ParsedInst::Then),
-            "else" => inst.push(ParsedInst::Otherwise),
+            "then" => ParsedInst::Then,
+            "else" => ParsedInst::Otherwise,
             "set" => {
                 let arg1 = self.parse_reg()?;
                 self.consume(Comma)?;
-                let arg2 = self.consume_int()? as u32;
+                let arg2 = self.parse_expr(-32768, 65535)?;
 
-                if arg2 > 255 {
-                    inst.push(ParsedInst::SetShort { dst: arg1, val: arg2 as u16 });
-                } else {
-                    inst.push(ParsedInst::SetByte { dst: arg1, val: arg2 as u8 });
+                match arg2 {
+                    // `SetByte` zero extends, so negative values need the long form
+                    Expr::Int(val) if (0..=255).contains(&val) => ParsedInst::SetByte { dst: arg1, val: val as u8 },
+                    _ => ParsedInst::Set { dst: arg1, val: arg2 },
                 }
             }
+            "la" => {
+                // Always the long form, so the size doesn't depend on the address
+                let arg1 = self.parse_reg()?;
+                self.consume(Comma)?;
+                let arg2 = self.parse_expr(-32768, 65535)?;
+                ParsedInst::SetShort { dst: arg1, val: arg2 }
+            }
             "push" => {
                 let arg1 = self.parse_reg()?;
-                inst.push(ParsedInst::Push { src: arg1 })
+                ParsedInst::Push { src: arg1 }
             }
             "pop" => {
                 let arg1 = self.parse_reg()?;
-                inst.push(ParsedInst::Pop { dst: arg1 })
+                ParsedInst::Pop { dst: arg1 }
             }
             "add" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::Add { dst: arg1, src: arg2 })
+                ParsedInst::Add { dst: arg1, src: arg2 }
             }
             "sub" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::Sub { dst: arg1, src: arg2 })
+                ParsedInst::Sub { dst: arg1, src: arg2 }
             }
             "mul" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::Mul { dst: arg1, src: arg2 })
+                ParsedInst::Mul { dst: arg1, src: arg2 }
             }
             "div" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::Div { dst: arg1, src: arg2 })
+                ParsedInst::Div { dst: arg1, src: arg2 }
             }
             "mod" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::Mod { dst: arg1, src: arg2 })
+                ParsedInst::Mod { dst: arg1, src: arg2 }
             }
             "neg" => {
                 let arg1 = self.parse_reg()?;
-                inst.push(ParsedInst::Neg { dst: arg1 })
+                ParsedInst::Neg { dst: arg1 }
             }
             "gt" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::GreaterThan { left: arg1, right: arg2 })
+                ParsedInst::GreaterThan { left: arg1, right: arg2 }
             }
             "lt" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::LessThan { left: arg1, right: arg2 })
+                ParsedInst::LessThan { left: arg1, right: arg2 }
             }
             "ge" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::GreaterEqual { left: arg1, right: arg2 })
+                ParsedInst::GreaterEqual { left: arg1, right: arg2 }
             }
             "le" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::LessEqual { left: arg1, right: arg2 })
+                ParsedInst::LessEqual { left: arg1, right: arg2 }
             }
             "eq" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::Equal { left: arg1, right: arg2 })
+                ParsedInst::Equal { left: arg1, right: arg2 }
             }
             "neq" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::NotEqual { left: arg1, right: arg2 })
+                ParsedInst::NotEqual { left: arg1, right: arg2 }
             }
             "ret" => {
-                inst.push(ParsedInst::Return)
+                ParsedInst::Return
             }
             "call" => {
                 let label = self.consume_id()?;
-                inst.push(ParsedInst::Call { label })
+                ParsedInst::Call { label }
             }
             "mov" => {
                 let (arg1, arg2) = self.parse_2reg()?;
-                inst.push(ParsedInst::Mov { dst: arg1, src: arg2 })
+                ParsedInst::Mov { dst: arg1, src: arg2 }
             }
             "dbg" => {
                 let arg1 = self.parse_reg()?;
                 self.consume(Comma)?;
-                let mode = self.consume_int()? as u32;
-                inst.push(ParsedInst::Debug { src: arg1, mode })
+                let mode = self.parse_expr(0, 255)?;
+                ParsedInst::Debug { src: arg1, mode }
+            }
+            "ldw" => {
+                let (arg1, base, offset) = self.parse_mem()?;
+                ParsedInst::LoadWord { dst: arg1, base, offset }
+            }
+            "ldb" => {
+                let (arg1, base, offset) = self.parse_mem()?;
+                ParsedInst::LoadByte { dst: arg1, base, offset }
+            }
+            "stw" => {
+                let (arg1, base, offset) = self.parse_mem()?;
+                ParsedInst::StoreWord { src: arg1, base, offset }
+            }
+            "stb" => {
+                let (arg1, base, offset) = self.parse_mem()?;
+                ParsedInst::StoreByte { src: arg1, base, offset }
+            }
+            "and" => {
+                let (arg1, arg2) = self.parse_2reg()?;
+                ParsedInst::And { dst: arg1, src: arg2 }
+            }
+            "or" => {
+                let (arg1, arg2) = self.parse_2reg()?;
+                ParsedInst::Or { dst: arg1, src: arg2 }
+            }
+            "xor" => {
+                let (arg1, arg2) = self.parse_2reg()?;
+                ParsedInst::Xor { dst: arg1, src: arg2 }
+            }
+            "not" => {
+                let arg1 = self.parse_reg()?;
+                ParsedInst::Not { dst: arg1 }
+            }
+            "shl" => {
+                let (arg1, arg2) = self.parse_2reg()?;
+                ParsedInst::ShiftLeft { dst: arg1, src: arg2 }
+            }
+            "shr" => {
+                let (arg1, arg2) = self.parse_2reg()?;
+                ParsedInst::ShiftRight { dst: arg1, src: arg2 }
+            }
+            "sar" => {
+                let (arg1, arg2) = self.parse_2reg()?;
+                ParsedInst::ShiftRightArith { dst: arg1, src: arg2 }
             }
             _ => {
                 if self.expect(Colon).is_ok() {
                     self.consume(Colon)?;
-                    inst.push(ParsedInst::Label { label: name });
+                    ParsedInst::Label { label: name }
                 } else {
-                    return Err(format!("Found unexpected symbol {:?}", name));
+                    return Err(self.error_at(name_span, format!("Found unexpected symbol {:?}", name)));
                 }
             }
+        };
+        Ok(inst)
+    }
+
+    fn parse_directive(&mut self) -> Result<ParsedInst, Diagnostic> {
+        let Token(_, span) = *self.tk(self.pos);
+        let name = self.str(span).to_string();
+        self.pos += 1;
+
+        let inst = match name.as_ref() {
+            ".byte" => {
+                let values = self.parse_list(|parser| parser.parse_expr(-128, 255))?;
+                ParsedInst::Byte { values }
+            }
+            ".word" => {
+                let values = self.parse_list(|parser| parser.parse_expr(-32768, 65535))?;
+                ParsedInst::Word { values }
+            }
+            ".string" => {
+                // Strings are zero terminated
+                let mut bytes = self.consume_string()?;
+                bytes.push(0);
+                ParsedInst::Byte { values: bytes.into_iter().map(|byte| Expr::Int(byte as i32)).collect() }
+            }
+            ".zero" => {
+                let len = self.parse_expr(0, 65535)?;
+                ParsedInst::Zero { len }
+            }
+            ".equ" => {
+                let name = self.consume_id()?;
+                self.consume(Comma)?;
+                let value = self.parse_expr(i32::MIN, i32::MAX)?;
+                ParsedInst::Equ { name, value }
+            }
+            ".entry" => {
+                let label = self.consume_id()?;
+                ParsedInst::Entry { label }
+            }
+            ".noexit" => ParsedInst::NoExit,
+            ".text" => ParsedInst::Section { kind: SectionKind::Text },
+            ".data" => ParsedInst::Section { kind: SectionKind::Data },
+            ".bss" => ParsedInst::Section { kind: SectionKind::Bss },
+            ".global" => {
+                let names = self.parse_list(|parser| parser.consume_id())?;
+                ParsedInst::Global { names }
+            }
+            _ => return Err(self.error_at(span, format!("Unknown directive {:?}", name))),
+        };
+        Ok(inst)
+    }
+
+    // item [, item]*
+    fn parse_list<T>(&mut self, item: impl Fn(&mut Self) -> Result<T, Diagnostic>) -> Result<Vec<T>, Diagnostic> {
+        let mut items = vec![item(self)?];
+
+        while self.expect(Comma).is_ok() {
+            self.consume(Comma)?;
+            items.push(item(self)?);
         }
-        if self.tk(self.pos).0 != Eof {
-            self.consume(NewLine)?;
-        }
-        Ok(())
+        Ok(items)
     }
 
-    fn parse_reg(&mut self) -> Result<u32, String> {
-        let text = self.consume_id()?;
-        match text.as_ref() {
+    fn parse_reg(&mut self) -> Result<u32, Diagnostic> {
+        let text = self.expect_id()?;
+        let reg = match text.as_ref() {
             "zero" | "z" => Ok(0),
             "$0" | "a" => Ok(1),
             "$1" | "b" => Ok(2),
@@ -295,11 +700,13 @@ impl<'a> Parser<'a> {
             "$12" | "m" => Ok(13),
             "at" => Ok(14),
             "sp" => Ok(15),
-            _ => Err(format!("Expected register name, found {:?}", text))
-        }
+            _ => Err(self.error(format!("Expected register name, found {:?}", text)))
+        };
+        self.pos += 1;
+        reg
     }
 
-    fn parse_2reg(&mut self) -> Result<(u32, u32), String> {
+    fn parse_2reg(&mut self) -> Result<(u32, u32), Diagnostic> {
         let arg1 = self.parse_reg()?;
         self.consume(Comma)?;
         let arg2 = self.parse_reg()?;
@@ -307,151 +714,805 @@ impl<'a> Parser<'a> {
         Ok((arg1, arg2))
     }
 
-    fn consume_int(&mut self) -> Result<i32, String> {
+    // reg, base [, offset]
+    fn parse_mem(&mut self) -> Result<(u32, u32, Expr), Diagnostic> {
+        let (arg1, base) = self.parse_2reg()?;
+
+        if self.expect(Comma).is_err() {
+            return Ok((arg1, base, Expr::Int(0)));
+        }
+        self.consume(Comma)?;
+        let offset = self.parse_expr(0, 255)?;
+
+        Ok((arg1, base, offset))
+    }
+
+    // Expressions without symbols are folded and range checked here, the rest by the compiler
+    fn parse_expr(&mut self, min: i32, max: i32) -> Result<Expr, Diagnostic> {
+        let start = self.tk(self.pos).1 .0;
+        let expr = self.parse_binary(0)?;
+        let span = (start, self.tk(self.pos - 1).1 .1);
+
+        if expr.has_symbols() {
+            return Ok(expr);
+        }
+        let value = expr.eval(&|_| unreachable!()).map_err(|message| self.error_at(span, message))?;
+
+        if value < min || value > max {
+            return Err(self.error_at(span, format!("Value out of range ({} to {}): {}", min, max, value)));
+        }
+        Ok(Expr::Int(value))
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, Diagnostic> {
+        if level == BINARY_OPERATORS.len() {
+            return self.parse_unary();
+        }
+        let mut left = self.parse_binary(level + 1)?;
+
+        while let Some(op) = self.binary_operator(level) {
+            self.pos += 1;
+            let right = self.parse_binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn binary_operator(&self, level: usize) -> Option<Operator> {
+        let Token(token_type, span) = *self.tk(self.pos);
+        if token_type != Op {
+            return None;
+        }
+        let text = self.str(span);
+        BINARY_OPERATORS[level].iter().find(|(symbol, _)| *symbol == text).map(|(_, op)| *op)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, Diagnostic> {
+        let Token(token_type, span) = *self.tk(self.pos);
+
+        match token_type {
+            Op => {
+                let op = match self.str(span).as_ref() {
+                    "-" => Operator::Neg,
+                    "~" => Operator::Not,
+                    other => return Err(self.error(format!("Unexpected operator {:?}", other))),
+                };
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+            }
+            LParen => {
+                self.pos += 1;
+                let expr = self.parse_binary(0)?;
+                self.consume(RParen)?;
+                Ok(expr)
+            }
+            Id => Ok(Expr::Symbol(self.consume_id()?)),
+            _ => Ok(Expr::Int(self.consume_int()?)),
+        }
+    }
+
+    fn consume_string(&mut self) -> Result<Vec<u8>, Diagnostic> {
+        let Token(token_type, span) = *self.tk(self.pos);
+        if token_type != Str {
+            return Err(self.error(format!("Expected Str but found {:?}", token_type)));
+        }
+
+        let text = &self.source[span.0..span.1];
+        if text.len() < 2 || text[text.len() - 1] != b'"' {
+            return Err(self.error("Unterminated string".to_string()));
+        }
+
+        let bytes = self.unescape(&text[1..text.len() - 1])?;
+        self.pos += 1;
+        Ok(bytes)
+    }
+
+    fn unescape(&self, text: &[u8]) -> Result<Vec<u8>, Diagnostic> {
+        let mut bytes = Vec::new();
+        let mut chars = text.iter();
+
+        while let Some(c) = chars.next() {
+            if *c != b'\\' {
+                bytes.push(*c);
+                continue;
+            }
+            bytes.push(match chars.next() {
+                Some(b'n') => b'\n',
+                Some(b't') => b'\t',
+                Some(b'r') => b'\r',
+                Some(b'0') => 0,
+                Some(b'\\') => b'\\',
+                Some(b'"') => b'"',
+                Some(b'\'') => b'\'',
+                other => {
+                    let escape = other.map(|c| (*c as char).to_string()).unwrap_or_default();
+                    return Err(self.error(format!("Unknown escape sequence \\{}", escape)));
+                }
+            });
+        }
+        Ok(bytes)
+    }
+
+    fn consume_int(&mut self) -> Result<i32, Diagnostic> {
         let name = self.expect_int()?;
         self.pos += 1;
         Ok(name)
     }
 
-    fn expect_int(&self) -> Result<i32, String> {
+    fn expect_int(&self) -> Result<i32, Diagnostic> {
         let Token(token_type, span) = self.tk(self.pos);
         if token_type != &Int {
-            Err(format!("Expected Int but found {:?}", token_type))
+            Err(self.error(format!("Expected Int but found {:?}", token_type)))
         } else {
-            let digits = self.str(*span);
-            digits.parse::<i32>().map_err(|err| format!("Unable to parse integer: {:?}", err))
+            let text = self.source[span.0..span.1].to_vec();
+            if text[0] == b'\'' {
+                return self.parse_char(&text);
+            }
+
+            let digits = self.str(*span).replace('_', "");
+            let value = if let Some(hex) = digits.strip_prefix("0x") {
+                i32::from_str_radix(hex, 16)
+            } else if let Some(binary) = digits.strip_prefix("0b") {
+                i32::from_str_radix(binary, 2)
+            } else {
+                digits.parse::<i32>()
+            };
+            value.map_err(|err| self.error(format!("Unable to parse integer {:?}: {}", self.str(*span), err)))
+        }
+    }
+
+    // 'A' or an escape like '\n'
+    fn parse_char(&self, text: &[u8]) -> Result<i32, Diagnostic> {
+        if text.len() < 2 || text[text.len() - 1] != b'\'' {
+            return Err(self.error("Unterminated character literal".to_string()));
+        }
+
+        match self.unescape(&text[1..text.len() - 1])?[..] {
+            [byte] => Ok(byte as i32),
+            _ => Err(self.error("Character literals must be a single byte".to_string())),
         }
     }
 
-    fn consume_id(&mut self) -> Result<String, String> {
+    fn consume_id(&mut self) -> Result<String, Diagnostic> {
         let name = self.expect_id()?;
         self.pos += 1;
         Ok(name)
     }
 
-    fn expect_id(&self) -> Result<String, String> {
+    fn expect_id(&self) -> Result<String, Diagnostic> {
         let Token(token_type, span) = self.tk(self.pos);
         if token_type != &Id {
-            Err(format!("Expected Id but found {:?}", token_type))
+            Err(self.error(format!("Expected Id but found {:?}", token_type)))
         } else {
             Ok(self.str(*span).to_string())
         }
     }
 
-    fn consume(&mut self, ty: TokenType) -> Result<(), String> {
+    fn consume(&mut self, ty: TokenType) -> Result<(), Diagnostic> {
         self.expect(ty)?;
         self.pos += 1;
         Ok(())
     }
 
-    fn expect(&self, ty: TokenType) -> Result<(), String> {
-        let Token(token_type, span) = self.tk(self.pos);
+    fn expect(&self, ty: TokenType) -> Result<(), Diagnostic> {
+        let Token(token_type, _) = self.tk(self.pos);
         if token_type != &ty {
-            Err(format!("Expected {:?} but found {:?} at {:?}", ty, token_type, span))
+            Err(self.error(format!("Expected {:?} but found {:?}", ty, token_type)))
         } else {
             Ok(())
         }
     }
 
+    // Error pointing at the current token
+    fn error(&self, message: String) -> Diagnostic {
+        self.error_at(self.tk(self.pos).1, message)
+    }
+
+    fn error_at(&self, span: Span, message: String) -> Diagnostic {
+        self.file.error(span, message)
+    }
+
     fn tk(&self, pos: usize) -> &Token {
         let pos = pos.min(self.tokens.len());
         &self.tokens[pos]
     }
 
-    fn str(&self, span: (usize, usize)) -> Cow<str> {
+    fn str(&self, span: (usize, usize)) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.source[span.0..span.1])
     }
 }
 
 pub struct Compiler {
     symbol_table: HashMap<String, usize>,
+    // Section every label is defined in
+    label_sections: HashMap<String, SectionKind>,
+    constants: HashMap<String, Expr>,
+    // Labels exported with `.global`
+    globals: Vec<(String, Span)>,
+    // Label given to `.entry`
+    entry: Option<(String, Span)>,
+    // Whether an `exit` is added at the end of the text, `.noexit` leaves it out
+    implicit_exit: bool,
+    // Leaves absolute addresses to the linker
+    relocatable: bool,
+    section: SectionKind,
+    // Index in `buffer` where the data and bss sections start, text is first
+    section_starts: [usize; 2],
+    // Address of every instruction in `buffer` after `emit`, and the end address
+    addresses: Vec<usize>,
+    // Statement that produced every instruction in `buffer`, none for the implicit exit
+    spans: Vec<Option<Span>>,
     buffer: Vec<PrecompiledInst>,
     pos: usize,
 }
 
 #[derive(Clone, Debug)]
 pub enum PrecompiledInst {
-    JumpPlaceHolder(String, usize),
-    CallPlaceHolder(String),
+    Label(String),
+    Data(Vec<u8>),
+    JumpPlaceHolder(String, Span),
+    CallPlaceHolder(String, Span),
+    ExprPlaceHolder(Vec<u8>, Vec<Field>, Span),
     Compiled1(Inst),
     Compiled2(Inst, u8),
     Compiled3(Inst, u8, u8),
     Compiled4(Inst, u8, u8, u8),
 }
 
+// Big-endian immediate of `width` bytes at `offset`, filled in by the compiler
+#[derive(Clone, Debug)]
+pub struct Field {
+    pub offset: usize,
+    pub width: usize,
+    pub expr: Expr,
+    // Accepts negative values, stored as two's complement
+    pub signed: bool,
+}
+
+impl PrecompiledInst {
+    // Size in bytes, jumps are assumed to be short
+    pub fn len(&self) -> usize {
+        match self {
+            PrecompiledInst::Label(_) => 0,
+            PrecompiledInst::Data(bytes) => bytes.len(),
+            PrecompiledInst::JumpPlaceHolder(..) => Inst::JumpFw.len() as usize,
+            PrecompiledInst::CallPlaceHolder(..) => Inst::Call.len() as usize,
+            PrecompiledInst::ExprPlaceHolder(bytes, ..) => bytes.len(),
+            PrecompiledInst::Compiled1(i)
+            | PrecompiledInst::Compiled2(i, _)
+            | PrecompiledInst::Compiled3(i, _, _)
+            | PrecompiledInst::Compiled4(i, _, _, _) => i.len() as usize,
+        }
+    }
+}
+
 impl Compiler {
     pub fn new() -> Compiler {
         Compiler {
             symbol_table: HashMap::new(),
+            label_sections: HashMap::new(),
+            constants: HashMap::new(),
+            globals: Vec::new(),
+            entry: None,
+            implicit_exit: true,
+            relocatable: false,
+            section: SectionKind::Text,
+            section_starts: [0, 0],
+            addresses: Vec::new(),
+            spans: Vec::new(),
             buffer: Vec::new(),
             pos: 0,
         }
     }
 
-    pub fn compile(&mut self, insts: &Vec<ParsedInst>) -> Result<Vec<u8>, String> {
+    pub fn compile(&mut self, file: &SourceFile, statements: &[Statement]) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        self.precompile(file, statements)?;
+        let (asm, _) = self.emit(file)?;
+        Ok(asm)
+    }
+
+    // Code is generated for address 0, every absolute address gets a relocation.
+    // Labels that aren't defined become imports. The linker adds the exit at the end of the text.
+    pub fn compile_object(&mut self, file: &SourceFile, statements: &[Statement]) -> Result<Object, Vec<Diagnostic>> {
+        self.relocatable = true;
+        self.implicit_exit = false;
+        self.precompile(file, statements)?;
+        let (mut code, relocations) = self.emit(file)?;
+
+        let [data, bss] = self.section_starts;
+        let text_size = self.addresses[data];
+        let bss_size = code.len() - self.addresses[bss];
+        code.truncate(self.addresses[bss]);
+
+        let mut symbols: Vec<Symbol> = self.symbol_table.iter()
+            .map(|(name, offset)| Symbol {
+                name: name.clone(),
+                offset: *offset as u16,
+                section: self.label_sections[name],
+                global: self.globals.iter().any(|(global, _)| global == name),
+            })
+            .collect();
+        symbols.sort_by(|a, b| (a.offset, &a.name).cmp(&(b.offset, &b.name)));
+
+        let mut imports: Vec<String> = relocations.iter()
+            .map(|relocation| relocation.symbol.clone())
+            .filter(|symbol| !self.symbol_table.contains_key(symbol))
+            .collect();
+        imports.sort();
+        imports.dedup();
+
+        let entry = self.entry.as_ref().map(|(label, _)| label.clone());
+        Ok(Object { name: file.name.clone(), code, text_size, bss_size, symbols, imports, relocations, entry })
+    }
+
+    pub fn compile_image(&mut self, file: &SourceFile, statements: &[Statement]) -> Result<Image, Vec<Diagnostic>> {
+        self.precompile(file, statements)?;
+        let (memory, _) = self.emit(file)?;
+
+        let [data, bss] = self.section_starts;
+        let bounds = [self.addresses[0], self.addresses[data], self.addresses[bss], memory.len()];
+        let sections = [SectionKind::Text, SectionKind::Data, SectionKind::Bss].iter().enumerate()
+            .filter(|(i, _)| bounds[i + 1] > bounds[*i])
+            .map(|(i, kind)| Section {
+                kind: *kind,
+                address: bounds[i] as u16,
+                size: bounds[i + 1] - bounds[i],
+                bytes: if *kind == SectionKind::Bss { Vec::new() } else { memory[bounds[i]..bounds[i + 1]].to_vec() },
+            })
+            .collect();
+
+        let mut symbols: Vec<(String, u16)> = self.symbol_table.iter()
+            .map(|(name, address)| (name.clone(), *address as u16))
+            .collect();
+        symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+
+        // `.entry` or else a `main` label, the start of the program without either
+        let (entry, span) = match &self.entry {
+            Some((label, span)) => (self.symbol_table[label], *span),
+            None => (self.symbol_table.get("main").copied().unwrap_or(0), (0, 0)),
+        };
+        if entry >= bounds[1] {
+            return Err(vec![file.error(span, format!("Entry point at {:#06X} is not in the text section", entry))]);
+        }
+
+        Ok(Image { entry: entry as u16, memory_size: memory_size_for(memory.len()), sections, symbols })
+    }
+
+    // Every line the user wrote next to its address and encoding, then the symbols. Lines that
+    // the preprocessor rewrote are followed by the lines they became, marked with `+`.
+    // `memory` is the output of `emit`.
+    pub fn listing(&self, file: &SourceFile, memory: &[u8]) -> String {
+        let line_text = |text: &[u8]| String::from_utf8_lossy(text).trim_end_matches('\r').to_string();
+        let lines_of = |text: &[u8]| {
+            let mut lines: Vec<String> = text.split(|c| *c == b'\n').map(line_text).collect();
+            if lines.last().is_some_and(String::is_empty) {
+                lines.pop();
+            }
+            lines
+        };
+
+        // Buffer entries by line of the preprocessed text
+        let mut entries = vec![Vec::new(); file.line_count()];
+        let mut implicit = Vec::new();
+        for (i, span) in self.spans.iter().enumerate() {
+            match span {
+                Some(span) => entries[file.line_index(span.0)].push(i),
+                None => implicit.push(i),
+            }
+        }
+
+        // Preprocessed lines by the file and line they were written at
+        let preprocessed = lines_of(&file.text);
+        let mut generated: HashMap<(String, usize), Vec<usize>> = HashMap::new();
+        for index in 0..preprocessed.len() {
+            let origin = file.origin(index);
+            generated.entry((origin.file, origin.line)).or_default().push(index);
+        }
+
+        let root = [Source { name: file.name.clone(), text: file.text.clone(), includes: Vec::new() }];
+        let sources = if file.sources.is_empty() { &root[..] } else { &file.sources[..] };
+        let source_lines: Vec<Vec<String>> = sources.iter().map(|source| lines_of(&source.text)).collect();
+
+        let mut out = String::new();
+        let mut current_file = &file.name;
+        // (source, line index) to list next, included files are pushed on top of the includer
+        let mut stack = vec![(0, 0)];
+        while let Some((source, index)) = stack.pop() {
+            let Source { name, includes, .. } = &sources[source];
+            let text = match source_lines[source].get(index) {
+                Some(text) => text,
+                None => continue,
+            };
+            stack.push((source, index + 1));
+
+            if name != current_file {
+                out += &format!("; {}\n", name);
+                current_file = name;
+            }
+            let lines = generated.get(&(name.clone(), index + 1)).map_or(&[][..], Vec::as_slice);
+            match lines {
+                [line] if preprocessed[*line] == *text => self.list_line(&mut out, memory, &entries[*line], index + 1, text),
+                _ => {
+                    self.list_line(&mut out, memory, &[], index + 1, text);
+                    for line in lines.iter().filter(|line| !preprocessed[**line].trim().is_empty()) {
+                        self.list_line(&mut out, memory, &entries[*line], 0, &format!("+ {}", preprocessed[*line].trim()));
+                    }
+                }
+            }
+
+            if let Some((_, included)) = includes.iter().find(|(line, _)| *line == index) {
+                stack.push((*included, 0));
+            }
+        }
+        if !implicit.is_empty() {
+            self.list_line(&mut out, memory, &implicit, 0, "exit ; implicit");
+        }
+
+        out += "\nSymbols:\n";
+        let mut symbols: Vec<(&String, &usize)> = self.symbol_table.iter().collect();
+        symbols.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
+        for (name, address) in symbols {
+            out += &format!("{:04X}  {}\n", address, name);
+        }
+
+        let mut constants: Vec<&String> = self.constants.keys().collect();
+        constants.sort();
+        for name in constants {
+            if let Ok(value) = self.eval(&self.constants[name], true, &[]) {
+                out += &format!("{:<6}{} = {}\n", "", name, value);
+            }
+        }
+        out
+    }
+
+    // Text and data lines that produced bytes, in address order. Call after `emit`.
+    pub fn line_table(&self, file: &SourceFile) -> Vec<SourceLine> {
+        let mut lines = Vec::new();
+
+        for (i, span) in self.spans.iter().enumerate().take(self.section_starts[1]) {
+            let address = self.addresses[i];
+            if let Some(span) = span.filter(|_| self.addresses[i + 1] > address) {
+                let index = file.line_index(span.0);
+                let (start, end) = file.line_bounds(index);
+                let origin = file.origin(index);
+
+                lines.push(SourceLine {
+                    address: address as u16,
+                    file: origin.file,
+                    line: origin.line,
+                    text: String::from_utf8_lossy(&file.text[start..end]).trim().to_string(),
+                });
+            }
+        }
+        lines
+    }
+
+    // Bytes are shown 8 to a row, bss space isn't
+    fn list_line(&self, out: &mut String, memory: &[u8], entries: &[usize], line: usize, text: &str) {
+        const PER_ROW: usize = 8;
+        let line = if line == 0 { String::new() } else { line.to_string() };
+
+        let (start, bytes) = match (entries.first(), entries.last()) {
+            (Some(first), Some(last)) => {
+                let start = self.addresses[*first];
+                let end = if *first >= self.section_starts[1] { start } else { self.addresses[last + 1] };
+                (Some(start), &memory[start..end])
+            }
+            _ => (None, &memory[..0]),
+        };
+        let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ");
+        let address = start.map(|start| format!("{:04X}", start)).unwrap_or_default();
+
+        *out += &format!("{:<4}  {:<23}{:>5}  {}\n", address, hex(&bytes[..bytes.len().min(PER_ROW)]), line, text);
+        for (i, row) in bytes.chunks(PER_ROW).enumerate().skip(1) {
+            *out += &format!("{:04X}  {}\n", start.unwrap() + i * PER_ROW, hex(row));
+        }
+    }
+
+    fn emit(&mut self, file: &SourceFile) -> Result<(Vec<u8>, Vec<Relocation>), Vec<Diagnostic>> {
         let mut asm = Vec::new();
-        self.precompile(insts);
+        let mut relocations = Vec::new();
+        let mut errors = Vec::new();
+        let long_jumps = self.layout(file)?;
+        self.addresses.clear();
 
-        for inst in &self.buffer {
+        for (inst, long) in self.buffer.iter().zip(long_jumps) {
+            self.addresses.push(asm.len());
             match inst {
-                PrecompiledInst::JumpPlaceHolder(label, pos) => {
-                    let target = *self.symbol_table.get(label)
-                        .ok_or_else(|| format!("Jump to invalid label: {:?}", label))?;
-                    let diff = (target as isize) - (*pos as isize);
+                PrecompiledInst::Label(_) => {}
+                PrecompiledInst::Data(bytes) => asm.extend_from_slice(bytes),
+                PrecompiledInst::JumpPlaceHolder(label, span) => {
+                    let target = match self.symbol_table.get(label) {
+                        Some(target) => *target,
+                        // Imported, `layout` made it long
+                        None if self.relocatable => 0,
+                        None => {
+                            errors.push(file.error(*span, format!("Jump to invalid label: {:?}", label)));
+                            continue;
+                        }
+                    };
 
-                    if diff > 0 {
-                        asm.push(Inst::JumpFw as u8);
+                    if long {
+                        asm.push(Inst::Jump as u8);
+                        self.push_address(&mut asm, &mut relocations, label, target);
                     } else {
-                        asm.push(Inst::JumpBw as u8);
+                        // Short jumps are relative to the end of the instruction
+                        let diff = (target as isize) - (asm.len() as isize + 2);
+
+                        if diff >= 0 {
+                            asm.push(Inst::JumpFw as u8);
+                        } else {
+                            asm.push(Inst::JumpBw as u8);
+                        }
+                        asm.push(diff.unsigned_abs() as u8);
                     }
-                    asm.push(diff as u8);
                 }
-                PrecompiledInst::CallPlaceHolder(label) => {
-                    let target = *self.symbol_table.get(label)
-                        .ok_or_else(|| format!("Call to invalid label: {:?}", label))?;
+                PrecompiledInst::CallPlaceHolder(label, span) => {
+                    let target = match self.symbol_table.get(label) {
+                        Some(target) => *target,
+                        None if self.relocatable => 0,
+                        None => {
+                            errors.push(file.error(*span, format!("Call to invalid label: {:?}", label)));
+                            continue;
+                        }
+                    };
 
                     asm.push(Inst::Call as u8);
-                    asm.push((target >> 8) as u8);
-                    asm.push(target as u8);
+                    self.push_address(&mut asm, &mut relocations, label, target);
+                }
+                PrecompiledInst::ExprPlaceHolder(bytes, fields, span) => {
+                    let mut bytes = bytes.clone();
+
+                    for field in fields {
+                        match self.resolve(field) {
+                            Ok((Some(symbol), addend)) => relocations.push(Relocation {
+                                offset: (asm.len() + field.offset) as u16,
+                                symbol,
+                                addend,
+                            }),
+                            Ok((None, value)) => {
+                                for i in 0..field.width {
+                                    bytes[field.offset + i] = (value >> (8 * (field.width - 1 - i))) as u8;
+                                }
+                            }
+                            Err(message) => errors.push(file.error(*span, message)),
+                        }
+                    }
+                    asm.extend(bytes);
                 }
                 PrecompiledInst::Compiled1(i) => {
                     asm.push(*i as u8);
                 }
                 PrecompiledInst::Compiled2(i, a) => {
                     asm.push(*i as u8);
-                    asm.push(*a as u8);
+                    asm.push(*a);
                 }
                 PrecompiledInst::Compiled3(i, a, b) => {
                     asm.push(*i as u8);
-                    asm.push(*a as u8);
-                    asm.push(*b as u8);
+                    asm.push(*a);
+                    asm.push(*b);
                 }
                 PrecompiledInst::Compiled4(i, a, b, c) => {
                     asm.push(*i as u8);
-                    asm.push(*a as u8);
-                    asm.push(*b as u8);
-                    asm.push(*c as u8);
+                    asm.push(*a);
+                    asm.push(*b);
+                    asm.push(*c);
                 }
             }
         }
 
-        Ok(asm)
+        self.addresses.push(asm.len());
+
+        if errors.is_empty() {
+            Ok((asm, relocations))
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Absolute address of `symbol`, patched by the linker in objects
+    fn push_address(&self, asm: &mut Vec<u8>, relocations: &mut Vec<Relocation>, symbol: &str, target: usize) {
+        if self.relocatable {
+            relocations.push(Relocation { offset: asm.len() as u16, symbol: symbol.to_string(), addend: 0 });
+        }
+        asm.push((target >> 8) as u8);
+        asm.push(target as u8);
+    }
+
+    // Either a plain value or `symbol + addend` for the linker
+    fn resolve(&self, field: &Field) -> Result<(Option<String>, i32), String> {
+        let value = if self.relocatable {
+            match self.eval_relocatable(&field.expr, &[])? {
+                (Some(symbol), _) if field.width != 2 => {
+                    return Err(format!("Address of {:?} doesn't fit in a byte", symbol));
+                }
+                (Some(symbol), addend) => return Ok((Some(symbol), addend)),
+                (None, value) => value,
+            }
+        } else {
+            self.eval(&field.expr, true, &[])?
+        };
+
+        let max = (1 << (8 * field.width)) - 1;
+        let min = if field.signed { -(1 << (8 * field.width - 1)) } else { 0 };
+        if value < min || value > max {
+            return Err(format!("Value out of range ({} to {}): {}", min, max, value));
+        }
+        Ok((None, value))
+    }
+
+    // Value of `expr` as `symbol + addend`. Only sums and label differences can be relocated.
+    // `active` holds the constants being evaluated, to report cycles.
+    fn eval_relocatable(&self, expr: &Expr, active: &[&str]) -> Result<(Option<String>, i32), String> {
+        let overflow = || "Arithmetic overflow in expression".to_string();
+
+        match expr {
+            Expr::Symbol(name) => match self.constants.get(name) {
+                Some(_) if active.contains(&name.as_str()) => Err(format!("Constant {:?} is defined in terms of itself", name)),
+                Some(value) => self.eval_relocatable(value, &[active, &[name.as_str()]].concat()),
+                None => Ok((Some(name.clone()), 0)),
+            },
+            Expr::Binary(Operator::Add, left, right) => {
+                match (self.eval_relocatable(left, active)?, self.eval_relocatable(right, active)?) {
+                    ((symbol, a), (None, b)) | ((None, a), (symbol, b)) => Ok((symbol, a.checked_add(b).ok_or_else(overflow)?)),
+                    ((Some(symbol), _), _) => Err(format!("Address of {:?} can't be relocated in this expression", symbol)),
+                }
+            }
+            Expr::Binary(Operator::Sub, left, right) => {
+                let (left, a) = self.eval_relocatable(left, active)?;
+                let (right, b) = self.eval_relocatable(right, active)?;
+                let diff = a.checked_sub(b).ok_or_else(overflow)?;
+
+                match (left, right) {
+                    (left, None) => Ok((left, diff)),
+                    // The distance between two local labels in the same section doesn't depend on where the object ends up
+                    (Some(left), Some(right)) if self.symbol_table.contains_key(&left) && self.symbol_table.contains_key(&right)
+                        && self.label_sections[&left] == self.label_sections[&right] => {
+                        let distance = self.symbol_table[&left] as i32 - self.symbol_table[&right] as i32;
+                        Ok((None, distance.checked_add(diff).ok_or_else(overflow)?))
+                    }
+                    (_, Some(symbol)) => Err(format!("Address of {:?} can't be relocated in this expression", symbol)),
+                }
+            }
+            Expr::Int(_) | Expr::Unary(..) | Expr::Binary(..) => {
+                let absolute = |expr: &Expr| match self.eval_relocatable(expr, active)? {
+                    (None, value) => Ok(Box::new(Expr::Int(value))),
+                    (Some(symbol), _) => Err(format!("Address of {:?} can't be relocated in this expression", symbol)),
+                };
+                let folded = match expr {
+                    Expr::Unary(op, expr) => Expr::Unary(*op, absolute(expr)?),
+                    Expr::Binary(op, left, right) => Expr::Binary(*op, absolute(left)?, absolute(right)?),
+                    _ => expr.clone(),
+                };
+                Ok((None, folded.eval(&|_| unreachable!())?))
+            }
+        }
+    }
+
+    // Labels are only looked up once `layout` has assigned their final addresses
+    fn eval(&self, expr: &Expr, labels: bool, active: &[&str]) -> Result<i32, String> {
+        expr.eval(&|name| {
+            if let Some(value) = self.constants.get(name) {
+                if active.contains(&name) {
+                    return Err(format!("Constant {:?} is defined in terms of itself", name));
+                }
+                return self.eval(value, labels, &[active, &[name]].concat());
+            }
+            match self.symbol_table.get(name) {
+                Some(address) if labels => Ok(*address as i32),
+                _ if !labels => Err(format!("Expected a constant, found {:?}", name)),
+                _ => Err(format!("Reference to undefined symbol: {:?}", name)),
+            }
+        })
+    }
+
+    // Decides which jumps need the long encoding and assigns the final label addresses.
+    // Jumps start short and only ever grow, so this converges.
+    fn layout(&mut self, file: &SourceFile) -> Result<Vec<bool>, Vec<Diagnostic>> {
+        let mut long_jumps = vec![false; self.buffer.len()];
+
+        loop {
+            let mut pos = 0;
+            let mut addresses = Vec::with_capacity(self.buffer.len());
+
+            for (inst, long) in self.buffer.iter().zip(&long_jumps) {
+                addresses.push(pos);
+                match inst {
+                    PrecompiledInst::Label(label) => { self.symbol_table.insert(label.clone(), pos); }
+                    PrecompiledInst::JumpPlaceHolder(..) if *long => pos += Inst::Jump.len() as usize,
+                    _ => pos += inst.len(),
+                }
+            }
+
+            if pos > MAX_MEMORY_SIZE {
+                return Err(vec![file.error((0, 0), format!("Program of {} bytes doesn't fit in the address space", pos))]);
+            }
+
+            let mut changed = false;
+            for (i, inst) in self.buffer.iter().enumerate() {
+                if let PrecompiledInst::JumpPlaceHolder(label, _) = inst {
+                    let target = match self.symbol_table.get(label) {
+                        // The linker moves data and bss away from the text
+                        Some(_) if self.relocatable && !long_jumps[i] && self.label_sections[label] != SectionKind::Text => {
+                            long_jumps[i] = true;
+                            changed = true;
+                            continue;
+                        }
+                        Some(target) => *target as isize,
+                        // Imports can be anywhere
+                        None if self.relocatable && !long_jumps[i] => {
+                            long_jumps[i] = true;
+                            changed = true;
+                            continue;
+                        }
+                        None => continue,
+                    };
+                    let diff = target - (addresses[i] as isize + 2);
+
+                    if !long_jumps[i] && !(-255..=255).contains(&diff) {
+                        long_jumps[i] = true;
+                        changed = true;
+                    }
+                }
+            }
+
+            if !changed {
+                return Ok(long_jumps);
+            }
+        }
     }
 
-    fn precompile(&mut self, insts: &Vec<ParsedInst>) -> Result<Vec<PrecompiledInst>, String> {
-        for inst in insts {
+    pub fn precompile(&mut self, file: &SourceFile, statements: &[Statement]) -> Result<Vec<PrecompiledInst>, Vec<Diagnostic>> {
+        let mut errors = Vec::new();
+
+        // Constants can be used before their definition
+        for Statement { inst, span } in statements {
+            if let ParsedInst::Equ { name, value } = inst {
+                if self.constants.insert(name.clone(), value.clone()).is_some() {
+                    errors.push(file.error(*span, format!("Duplicate constant: {:?}", name)));
+                }
+            }
+        }
+
+        // Section of every instruction in `buffer`
+        let mut sections = Vec::new();
+
+        for Statement { inst, span } in statements {
+            if self.section == SectionKind::Bss && !matches!(inst,
+                ParsedInst::Label { .. } | ParsedInst::Zero { .. } | ParsedInst::Equ { .. } | ParsedInst::Global { .. } | ParsedInst::Section { .. }) {
+                errors.push(file.error(*span, "Only labels and .zero are allowed in .bss".to_string()));
+                continue;
+            }
+
             match inst {
-                ParsedInst::Label { label } => { self.symbol_table.insert(label.clone(), self.pos); }
+                ParsedInst::Label { label } => {
+                    if self.constants.contains_key(label) {
+                        errors.push(file.error(*span, format!("Label {:?} is already defined as a constant", label)));
+                    }
+                    if self.symbol_table.insert(label.clone(), self.pos).is_some() {
+                        errors.push(file.error(*span, format!("Duplicate label: {:?}", label)));
+                    }
+                    self.label_sections.insert(label.clone(), self.section);
+                    self.buffer.push(PrecompiledInst::Label(label.clone()));
+                }
                 ParsedInst::Nop => self.inst_1(Inst::Nop),
                 ParsedInst::Exit => self.inst_1(Inst::Exit),
                 ParsedInst::Jump { label } => {
-                    self.buffer.push(PrecompiledInst::JumpPlaceHolder(label.clone(), self.pos));
+                    self.buffer.push(PrecompiledInst::JumpPlaceHolder(label.clone(), *span));
                     self.pos += 2;
                 }
+                ParsedInst::LongJump { label } => {
+                    let field = Field { offset: 1, width: 2, expr: Expr::Symbol(label.clone()), signed: false };
+                    self.inst_expr(vec![Inst::Jump as u8, 0, 0], vec![field], *span);
+                }
                 ParsedInst::Then => self.inst_1(Inst::Then),
                 ParsedInst::Otherwise => self.inst_1(Inst::Otherwise),
                 ParsedInst::SetByte { dst, val } => self.inst_3(Inst::SetByte, *dst as u8, *val),
-                ParsedInst::SetShort { dst, val } => self.inst_4(Inst::SetShort, *dst as u8, (*val >> 8) as u8, *val as u8),
+                ParsedInst::SetShort { dst, val } => self.inst_set_short(*dst, val, *span),
+                ParsedInst::Set { dst, val } => match self.eval(val, false, &[]) {
+                    Ok(val) if (0..=255).contains(&val) => self.inst_3(Inst::SetByte, *dst as u8, val as u8),
+                    _ => self.inst_set_short(*dst, val, *span),
+                }
                 ParsedInst::Push { src } => self.inst_2(Inst::Push, *src as u8),
                 ParsedInst::Pop { dst } => self.inst_2(Inst::Pop, *dst as u8),
                 ParsedInst::Add { dst, src } => self.inst_3(Inst::Add, *dst as u8, *src as u8),
@@ -468,16 +1529,112 @@ impl Compiler {
                 ParsedInst::NotEqual { left, right } => self.inst_3(Inst::NotEqual, *left as u8, *right as u8),
                 ParsedInst::Return => self.inst_1(Inst::Return),
                 ParsedInst::Call { label } => {
-                    self.buffer.push(PrecompiledInst::CallPlaceHolder(label.clone()));
+                    self.buffer.push(PrecompiledInst::CallPlaceHolder(label.clone(), *span));
                     self.pos += 3;
                 }
                 ParsedInst::Mov { dst, src } => self.inst_3(Inst::Mov, *dst as u8, *src as u8),
-                ParsedInst::Debug { src, mode } => self.inst_3(Inst::Debug, *src as u8, *mode as u8),
+                ParsedInst::Debug { src, mode } => {
+                    self.inst_expr(vec![Inst::Debug as u8, *src as u8, 0], vec![Field { offset: 2, width: 1, expr: mode.clone(), signed: false }], *span)
+                }
+                ParsedInst::LoadWord { dst, base, offset } => self.inst_mem(Inst::LoadWord, *dst, *base, offset, *span),
+                ParsedInst::LoadByte { dst, base, offset } => self.inst_mem(Inst::LoadByte, *dst, *base, offset, *span),
+                ParsedInst::StoreWord { src, base, offset } => self.inst_mem(Inst::StoreWord, *src, *base, offset, *span),
+                ParsedInst::StoreByte { src, base, offset } => self.inst_mem(Inst::StoreByte, *src, *base, offset, *span),
+                ParsedInst::And { dst, src } => self.inst_3(Inst::And, *dst as u8, *src as u8),
+                ParsedInst::Or { dst, src } => self.inst_3(Inst::Or, *dst as u8, *src as u8),
+                ParsedInst::Xor { dst, src } => self.inst_3(Inst::Xor, *dst as u8, *src as u8),
+                ParsedInst::Not { dst } => self.inst_2(Inst::Not, *dst as u8),
+                ParsedInst::ShiftLeft { dst, src } => self.inst_3(Inst::ShiftLeft, *dst as u8, *src as u8),
+                ParsedInst::ShiftRight { dst, src } => self.inst_3(Inst::ShiftRight, *dst as u8, *src as u8),
+                ParsedInst::ShiftRightArith { dst, src } => self.inst_3(Inst::ShiftRightArith, *dst as u8, *src as u8),
+                ParsedInst::Byte { values } => self.data_expr(values, 1, *span),
+                // Big-endian, like values on the stack and `SetShort` immediates
+                ParsedInst::Word { values } => self.data_expr(values, 2, *span),
+                // The size has to be known before labels are laid out
+                ParsedInst::Zero { len } => match self.eval(len, false, &[]) {
+                    Ok(len) if (0..=65535).contains(&len) => self.data(vec![0; len as usize]),
+                    Ok(len) => errors.push(file.error(*span, format!("Value out of range (0 to 65535): {}", len))),
+                    Err(message) => errors.push(file.error(*span, message)),
+                },
+                ParsedInst::Equ { .. } => {}
+                ParsedInst::Global { names } => {
+                    self.globals.extend(names.iter().map(|name| (name.clone(), *span)));
+                }
+                ParsedInst::Section { kind } => self.section = *kind,
+                ParsedInst::Entry { label } => {
+                    if self.entry.is_some() {
+                        errors.push(file.error(*span, "Duplicate .entry".to_string()));
+                    }
+                    self.entry = Some((label.clone(), *span));
+                }
+                ParsedInst::NoExit => self.implicit_exit = false,
             }
+            sections.resize(self.buffer.len(), self.section);
+            self.spans.resize(self.buffer.len(), Some(*span));
+        }
+
+        if self.implicit_exit {
+            self.inst_1(Inst::Exit);
+            sections.push(SectionKind::Text);
+            self.spans.push(None);
         }
 
-        self.inst_1(Inst::Exit);
-        Ok(self.buffer.clone())
+        // Sections are laid out one after another: text, data, then bss
+        let mut buffer = Vec::with_capacity(self.buffer.len());
+        let mut spans = Vec::with_capacity(self.buffer.len());
+        for (i, kind) in [SectionKind::Text, SectionKind::Data, SectionKind::Bss].iter().enumerate() {
+            if i > 0 {
+                self.section_starts[i - 1] = buffer.len();
+            }
+            for ((inst, span), _) in self.buffer.iter().zip(&self.spans).zip(&sections).filter(|(_, section)| *section == kind) {
+                buffer.push(inst.clone());
+                spans.push(*span);
+            }
+        }
+        self.buffer = buffer;
+        self.spans = spans;
+
+        for (name, span) in &self.globals {
+            if !self.symbol_table.contains_key(name) {
+                errors.push(file.error(*span, format!("Global label {:?} is not defined", name)));
+            }
+        }
+        if let Some((label, span)) = &self.entry {
+            if !self.symbol_table.contains_key(label) {
+                errors.push(file.error(*span, format!("Entry label {:?} is not defined", label)));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(self.buffer.clone())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn data(&mut self, bytes: Vec<u8>) {
+        self.pos += bytes.len();
+        self.buffer.push(PrecompiledInst::Data(bytes));
+    }
+
+    fn data_expr(&mut self, values: &[Expr], width: usize, span: Span) {
+        let fields = values.iter().enumerate()
+            .map(|(i, value)| Field { offset: i * width, width, expr: value.clone(), signed: true })
+            .collect();
+        self.inst_expr(vec![0; values.len() * width], fields, span);
+    }
+
+    fn inst_set_short(&mut self, dst: u32, val: &Expr, span: Span) {
+        self.inst_expr(vec![Inst::SetShort as u8, dst as u8, 0, 0], vec![Field { offset: 2, width: 2, expr: val.clone(), signed: true }], span);
+    }
+
+    fn inst_mem(&mut self, i: Inst, reg: u32, base: u32, offset: &Expr, span: Span) {
+        self.inst_expr(vec![i as u8, reg as u8, base as u8, 0], vec![Field { offset: 3, width: 1, expr: offset.clone(), signed: false }], span);
+    }
+
+    fn inst_expr(&mut self, bytes: Vec<u8>, fields: Vec<Field>, span: Span) {
+        self.pos += bytes.len();
+        self.buffer.push(PrecompiledInst::ExprPlaceHolder(bytes, fields, span));
     }
 
     fn inst_1(&mut self, i: Inst) {
@@ -0,0 +1,281 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use crate::assembler::SourceLine;
+use crate::disassembler::{decode, decode_all, Instruction, Line, REGISTER_NAMES};
+use crate::vm::{Inst, SP_REGISTER, Step, VM, WatchKind};
+
+const HELP: &str = "\
+break <location>         stop when execution reaches a label, address or file:line
+step                     execute one instruction
+next                     like step, but runs called subroutines to completion
+finish                   run until the current subroutine returns
+continue                 run until a breakpoint, exit or fault
+back                     undo the last instruction
+rcontinue                run backwards until a breakpoint or watchpoint
+regs                     show the registers
+mem <location> <n>       show n bytes of memory
+disasm [<location>]      show the instructions at pc or the given location
+set <reg> <value>        change a register, `pc` included
+watch <location> [<n>]   stop after writes to n bytes, 2 by default
+rwatch <location> [<n>]  stop after reads
+awatch <location> [<n>]  stop after reads and writes
+unwatch <id>             remove a watchpoint
+quit";
+
+// Instructions shown by `disasm`
+const DISASM_COUNT: usize = 8;
+
+pub struct Debugger {
+    vm: VM,
+    // Sorted by address
+    symbols: Vec<(String, u16)>,
+    lines: Vec<SourceLine>,
+    breakpoints: BTreeSet<u16>,
+    // Last command, repeated by an empty line
+    last: String,
+}
+
+impl Debugger {
+    // `vm` should have the program loaded, `symbols` and `lines` come from the assembler and may be empty
+    pub fn new(vm: VM, mut symbols: Vec<(String, u16)>, lines: Vec<SourceLine>) -> Debugger {
+        symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+        Debugger { vm, symbols, lines, breakpoints: BTreeSet::new(), last: String::new() }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    // Reads commands until `quit` or the end of the input
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> std::io::Result<()> {
+        writeln!(output, "{}", self.location(self.vm.pc()))?;
+        write!(output, "(vm) ")?;
+        output.flush()?;
+
+        for line in input.lines() {
+            let line = line?;
+            if matches!(line.trim(), "quit" | "q") {
+                break;
+            }
+            match self.command(&line) {
+                Ok(text) if text.is_empty() => {}
+                Ok(text) => writeln!(output, "{}", text)?,
+                Err(message) => writeln!(output, "error: {}", message)?,
+            }
+            write!(output, "(vm) ")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    // Runs one command and returns what to show
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let line = if line.trim().is_empty() { self.last.clone() } else { line.trim().to_string() };
+        self.last = line.clone();
+        let args: Vec<&str> = line.split_whitespace().collect();
+
+        match args.as_slice() {
+            [] => Ok(String::new()),
+            ["break" | "b", location] => {
+                let address = self.address(location)?;
+                self.breakpoints.insert(address);
+                Ok(format!("Breakpoint set at {}", self.location(address)))
+            }
+            ["step" | "s"] => Ok(self.run_until(|_, _| true)),
+            ["next" | "n"] => {
+                let pc = self.vm.pc();
+                match decode(self.vm.memory().get(pc as usize..).unwrap_or(&[]), pc) {
+                    Some(instruction) if instruction.inst == Inst::Call => {
+                        // Back at the instruction after the call with the same stack, recursion included
+                        let sp = self.vm.register(SP_REGISTER);
+                        let next = pc.wrapping_add(Inst::Call.len());
+                        Ok(self.run_until(|vm, _| vm.pc() == next && vm.register(SP_REGISTER) == sp))
+                    }
+                    _ => Ok(self.run_until(|_, _| true)),
+                }
+            }
+            ["finish"] => {
+                let sp = self.vm.register(SP_REGISTER);
+                Ok(self.run_until(|vm, inst| inst == Inst::Return && vm.register(SP_REGISTER) > sp))
+            }
+            ["continue" | "c"] => Ok(self.run_until(|_, _| false)),
+            ["back"] => match self.vm.step_back() {
+                Some(Step::Watchpoint(hit)) => Ok(format!("{}\n{}", hit, self.location(self.vm.pc()))),
+                Some(_) => Ok(self.location(self.vm.pc())),
+                None => Err("No history to step back into".to_string()),
+            },
+            ["rcontinue" | "rc"] => Ok(self.reverse_continue()),
+            ["regs"] => Ok(self.registers()),
+            ["mem", location, len] => {
+                let start = self.address(location)? as usize;
+                let len = parse_number(len)? as usize;
+                let bytes = self.vm.memory().get(start..start + len).ok_or("Range is outside of memory")?;
+
+                let rows: Vec<String> = bytes.chunks(16).enumerate()
+                    .map(|(i, row)| {
+                        let hex: Vec<String> = row.iter().map(|byte| format!("{:02X}", byte)).collect();
+                        format!("{:04X}  {}", start + i * 16, hex.join(" "))
+                    })
+                    .collect();
+                Ok(rows.join("\n"))
+            }
+            ["disasm"] => Ok(self.disassembly(self.vm.pc())),
+            ["disasm", location] => {
+                let address = self.address(location)?;
+                Ok(self.disassembly(address))
+            }
+            ["set", reg, value] => {
+                let value = parse_number(value)?;
+                if *reg == "pc" {
+                    self.vm.set_pc(value);
+                    return Ok(self.location(value));
+                }
+                let index = REGISTER_NAMES.iter().position(|name| name == reg)
+                    .ok_or_else(|| format!("Unknown register {:?}", reg))?;
+                self.vm.set_register(index, value);
+                Ok(String::new())
+            }
+            [command @ ("watch" | "rwatch" | "awatch"), location, len @ ..] if len.len() <= 1 => {
+                let start = self.address(location)? as usize;
+                let len = len.first().map_or(Ok(2), |len| parse_number(len))? as usize;
+                let kind = match *command {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::ReadWrite,
+                };
+                let id = self.vm.add_watchpoint(start, len, kind)?;
+                Ok(format!("Watchpoint {} on 0x{:04X}..0x{:04X}", id, start, start + len))
+            }
+            ["unwatch", id] => {
+                let id = parse_number(id)? as usize;
+                if self.vm.remove_watchpoint(id) {
+                    Ok(String::new())
+                } else {
+                    Err(format!("No watchpoint {}", id))
+                }
+            }
+            ["help" | "h"] => Ok(HELP.to_string()),
+            _ => Err(format!("Unknown command {:?}, try `help`", line)),
+        }
+    }
+
+    // Steps until `done` accepts the executed instruction, a breakpoint is reached or the program stops
+    fn run_until(&mut self, done: impl Fn(&VM, Inst) -> bool) -> String {
+        loop {
+            match self.vm.step() {
+                Ok(Step::Exited) => return "Program exited".to_string(),
+                Ok(Step::Watchpoint(hit)) => return format!("{}\n{}", hit, self.location(self.vm.pc())),
+                Ok(Step::Executed(inst)) => {
+                    let pc = self.vm.pc();
+                    if self.breakpoints.contains(&pc) {
+                        return format!("Breakpoint at {}", self.location(pc));
+                    }
+                    if done(&self.vm, inst) {
+                        return self.location(pc);
+                    }
+                }
+                Err(fault) => return format!("{}\n{}", fault, self.location(fault.pc)),
+            }
+        }
+    }
+
+    // Breakpoints are checked where execution stops going back, watchpoints on the undone instructions
+    fn reverse_continue(&mut self) -> String {
+        loop {
+            match self.vm.step_back() {
+                None => return format!("Reached the start of the history\n{}", self.location(self.vm.pc())),
+                Some(Step::Watchpoint(hit)) => return format!("{}\n{}", hit, self.location(self.vm.pc())),
+                Some(_) => {
+                    let pc = self.vm.pc();
+                    if self.breakpoints.contains(&pc) {
+                        return format!("Breakpoint at {}", self.location(pc));
+                    }
+                }
+            }
+        }
+    }
+
+    fn registers(&self) -> String {
+        let mut rows = vec![format!("pc  0x{:04X}", self.vm.pc())];
+        for (i, name) in REGISTER_NAMES.iter().enumerate() {
+            let value = self.vm.register(i);
+            rows.push(format!("{:<3} 0x{:04X} {:>6}", name, value, value as i16));
+        }
+        rows.join("\n")
+    }
+
+    fn disassembly(&self, start: u16) -> String {
+        // Instructions are at most 4 bytes
+        let memory = self.vm.memory();
+        let end = memory.len().min(start as usize + DISASM_COUNT * 4);
+        let mut rows = Vec::new();
+
+        for line in decode_all(memory.get(start as usize..end).unwrap_or(&[]), start).iter().take(DISASM_COUNT) {
+            let address = line.address();
+            for (name, _) in self.symbols.iter().filter(|(_, symbol)| *symbol == address) {
+                rows.push(format!("{}:", name));
+            }
+            let marker = if address == self.vm.pc() { "=>" } else { "" };
+            let text = match line {
+                Line::Inst(instruction) => self.render(instruction),
+                Line::Bytes { .. } => line.to_string(),
+            };
+            rows.push(format!("{:<2}  {:04X}  {}", marker, address, text));
+        }
+        rows.join("\n")
+    }
+
+    fn symbol_at(&self, address: u16) -> Option<&str> {
+        self.symbols.iter().find(|(_, symbol)| *symbol == address).map(|(name, _)| name.as_str())
+    }
+
+    fn render(&self, instruction: &Instruction) -> String {
+        instruction.render(&|address| self.symbol_at(address).map(|name| name.to_string()))
+    }
+
+    // "0x0005 <main+2> test.asm:6  set a, 3"
+    fn location(&self, address: u16) -> String {
+        let mut text = format!("0x{:04X}", address);
+
+        if let Some((name, symbol)) = self.symbols.iter().rev().find(|(_, symbol)| *symbol <= address) {
+            match address - symbol {
+                0 => text += &format!(" <{}>", name),
+                offset => text += &format!(" <{}+{}>", name, offset),
+            }
+        }
+        match self.lines.iter().find(|line| line.address == address) {
+            Some(line) => text += &format!(" {}:{}  {}", line.file, line.line, line.text),
+            None => {
+                if let Some(instruction) = decode(self.vm.memory().get(address as usize..).unwrap_or(&[]), address) {
+                    text += &format!("  {}", self.render(&instruction));
+                }
+            }
+        }
+        text
+    }
+
+    // A label, a number or file:line
+    fn address(&self, location: &str) -> Result<u16, String> {
+        if let Some((_, address)) = self.symbols.iter().find(|(name, _)| name == location) {
+            return Ok(*address);
+        }
+        if let Some((file, line)) = location.rsplit_once(':') {
+            let line = parse_number(line)? as usize;
+            return self.lines.iter()
+                .find(|source| source.line == line && (source.file == file || source.file.ends_with(&format!("/{}", file))))
+                .map(|source| source.address)
+                .ok_or_else(|| format!("No code at {}", location));
+        }
+        parse_number(location).map_err(|_| format!("Unknown location {:?}", location))
+    }
+}
+
+// Decimal or 0x hexadecimal
+fn parse_number(text: &str) -> Result<u16, String> {
+    let value = match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse::<u16>(),
+    };
+    value.map_err(|_| format!("Expected a number, found {:?}", text))
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+
+// Byte range (start, end) in the source text
+pub type Span = (usize, usize);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub file: String,
+    // 1-based
+    pub line: usize,
+    // 1-based, in bytes
+    pub column: usize,
+    // Source line with a caret under the span
+    pub snippet: String,
+}
+
+// Where a line of preprocessed text was written by the user
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Origin {
+    pub file: String,
+    pub line: usize,
+    // Name of the macro that produced the line
+    pub expansion: Option<String>,
+}
+
+// A file as the user wrote it, before preprocessing
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Source {
+    pub name: String,
+    pub text: Vec<u8>,
+    // Line index of every `.include` that read a file, with the index of that file in `sources`
+    pub includes: Vec<(usize, usize)>,
+}
+
+pub struct SourceFile {
+    pub name: String,
+    pub text: Vec<u8>,
+    // One entry per line of `text`, empty when the text is the original file
+    pub origins: Vec<Origin>,
+    // Files the text was read from, the root file first. Empty when the text is the original file.
+    pub sources: Vec<Source>,
+    // Offset of the start of every line of `text`
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, text: Vec<u8>) -> SourceFile {
+        let line_starts = std::iter::once(0)
+            .chain(text.iter().enumerate().filter(|(_, c)| **c == b'\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceFile { name: name.to_string(), text, origins: Vec::new(), sources: Vec::new(), line_starts }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    // Index of the line that contains `offset`
+    pub fn line_index(&self, offset: usize) -> usize {
+        self.line_starts.partition_point(|start| *start <= offset) - 1
+    }
+
+    // Start and end of line `index`, without the newline
+    pub fn line_bounds(&self, index: usize) -> Span {
+        let end = self.line_starts.get(index + 1).map_or(self.text.len(), |next| next - 1);
+        (self.line_starts[index], end)
+    }
+
+    // Where line `index` of `text` was written
+    pub fn origin(&self, index: usize) -> Origin {
+        self.origins.get(index).cloned()
+            .unwrap_or_else(|| Origin { file: self.name.clone(), line: index + 1, expansion: None })
+    }
+
+    pub fn error(&self, span: Span, message: String) -> Diagnostic {
+        self.diagnostic(Severity::Error, span, message)
+    }
+
+    pub fn warning(&self, span: Span, message: String) -> Diagnostic {
+        self.diagnostic(Severity::Warning, span, message)
+    }
+
+    pub fn diagnostic(&self, severity: Severity, span: Span, message: String) -> Diagnostic {
+        let start = span.0.min(self.text.len());
+        let index = self.line_index(start);
+        let (line_start, line_end) = self.line_bounds(index);
+
+        let Origin { file, line, expansion } = self.origin(index);
+
+        let text = String::from_utf8_lossy(&self.text[line_start..line_end]);
+        let text = text.trim_end_matches('\r');
+
+        // Keep tabs so the caret lines up with the source line
+        let padding: String = self.text[line_start..start].iter()
+            .map(|c| if *c == b'\t' { '\t' } else { ' ' })
+            .collect();
+        let width = span.1.min(line_end).saturating_sub(start).max(1);
+
+        let gutter = " ".repeat(line.to_string().len());
+        let mut snippet = format!(
+            "{} |\n{} | {}\n{} | {}{}",
+            gutter, line, text, gutter, padding, "^".repeat(width)
+        );
+        if let Some(name) = &expansion {
+            snippet += &format!("\n{} = note: in expansion of macro `{}`", gutter, name);
+        }
+
+        Diagnostic {
+            severity,
+            message,
+            file,
+            line,
+            column: start - line_start + 1,
+            snippet,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        writeln!(f, "{}--> {}:{}:{}", " ".repeat(self.line.to_string().len()), self.file, self.line, self.column)?;
+        write!(f, "{}", self.snippet)
+    }
+}
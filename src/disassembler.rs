@@ -0,0 +1,324 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::image::{Image, Section, SectionKind};
+use crate::vm::Inst;
+
+// Names accepted by the assembler, indexed by register number
+pub const REGISTER_NAMES: [&str; 16] = ["z", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "at", "sp"];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Operand {
+    Reg(u8),
+    Byte(u8),
+    Short(u16),
+    // Absolute address of a jump or call
+    Target(u16),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub inst: Inst,
+    pub operands: Vec<Operand>,
+}
+
+// Result of decoding one position, bytes that aren't a valid instruction are kept as data
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Line {
+    Inst(Instruction),
+    Bytes { address: u16, bytes: Vec<u8> },
+}
+
+impl Inst {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Inst::Nop => "nop",
+            Inst::Exit => "exit",
+            Inst::JumpFw | Inst::JumpBw => "jmp",
+            Inst::Then => "then",
+            Inst::Otherwise => "else",
+            Inst::SetByte => "set",
+            Inst::SetShort => "la",
+            Inst::Push => "push",
+            Inst::Pop => "pop",
+            Inst::Add => "add",
+            Inst::Sub => "sub",
+            Inst::Mul => "mul",
+            Inst::Div => "div",
+            Inst::Mod => "mod",
+            Inst::Neg => "neg",
+            Inst::GreaterThan => "gt",
+            Inst::LessThan => "lt",
+            Inst::GreaterEqual => "ge",
+            Inst::LessEqual => "le",
+            Inst::Equal => "eq",
+            Inst::NotEqual => "neq",
+            Inst::Return => "ret",
+            Inst::Call => "call",
+            Inst::Mov => "mov",
+            Inst::Debug => "dbg",
+            Inst::LoadWord => "ldw",
+            Inst::LoadByte => "ldb",
+            Inst::StoreWord => "stw",
+            Inst::StoreByte => "stb",
+            Inst::And => "and",
+            Inst::Or => "or",
+            Inst::Xor => "xor",
+            Inst::Not => "not",
+            Inst::ShiftLeft => "shl",
+            Inst::ShiftRight => "shr",
+            Inst::ShiftRightArith => "sar",
+            Inst::Jump => "ljmp",
+        }
+    }
+}
+
+// Decodes the instruction at the start of `bytes`, None for invalid opcodes or registers and truncated instructions
+pub fn decode(bytes: &[u8], address: u16) -> Option<Instruction> {
+    let inst = Inst::from_byte(*bytes.first()?)?;
+    let args = bytes.get(1..inst.len() as usize)?;
+    let reg = |i: usize| Some(args[i]).filter(|reg| (*reg as usize) < REGISTER_NAMES.len()).map(Operand::Reg);
+    let short = |i: usize| ((args[i] as u16) << 8) | args[i + 1] as u16;
+    // Relative jumps count from the end of the instruction, like the VM
+    let next = address.wrapping_add(inst.len());
+
+    let operands = match inst {
+        Inst::Nop | Inst::Exit | Inst::Then | Inst::Otherwise | Inst::Return => vec![],
+        Inst::JumpFw => vec![Operand::Target(next.wrapping_add(args[0] as u16))],
+        Inst::JumpBw => vec![Operand::Target(next.wrapping_sub(args[0] as u16))],
+        Inst::Call | Inst::Jump => vec![Operand::Target(short(0))],
+        Inst::SetByte | Inst::Debug => vec![reg(0)?, Operand::Byte(args[1])],
+        Inst::SetShort => vec![reg(0)?, Operand::Short(short(1))],
+        Inst::Push | Inst::Pop | Inst::Neg | Inst::Not => vec![reg(0)?],
+        Inst::LoadWord | Inst::LoadByte | Inst::StoreWord | Inst::StoreByte => vec![reg(0)?, reg(1)?, Operand::Byte(args[2])],
+        _ => vec![reg(0)?, reg(1)?],
+    };
+    Some(Instruction { address, inst, operands })
+}
+
+// Decodes `bytes` loaded at `start` one instruction after another
+pub fn decode_all(bytes: &[u8], start: u16) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let address = start.wrapping_add(pos as u16);
+        match decode(&bytes[pos..], address) {
+            Some(instruction) => {
+                pos += instruction.inst.len() as usize;
+                lines.push(Line::Inst(instruction));
+            }
+            None => {
+                lines.push(Line::Bytes { address, bytes: vec![bytes[pos]] });
+                pos += 1;
+            }
+        }
+    }
+    lines
+}
+
+impl Instruction {
+    pub fn target(&self) -> Option<u16> {
+        self.operands.iter().find_map(|operand| match operand {
+            Operand::Target(target) => Some(*target),
+            _ => None,
+        })
+    }
+
+    // Whether the assembler picks this encoding for `jmp`, it never emits backward jumps of 0 or wraps around
+    fn is_canonical(&self) -> bool {
+        let diff = self.target().map_or(0, |target| target as isize - (self.address as isize + 2));
+
+        match self.inst {
+            Inst::JumpFw => (0..=255).contains(&diff),
+            Inst::JumpBw => (-255..=-1).contains(&diff),
+            _ => true,
+        }
+    }
+
+    // Assembly syntax, with `symbol` naming jump and call targets and `la` values
+    pub fn render(&self, symbol: &dyn Fn(u16) -> Option<String>) -> String {
+        let operands: Vec<String> = self.operands.iter()
+            .map(|operand| match operand {
+                Operand::Reg(reg) => REGISTER_NAMES[*reg as usize].to_string(),
+                Operand::Byte(value) => value.to_string(),
+                Operand::Short(value) => symbol(*value).unwrap_or_else(|| format!("0x{:04X}", value)),
+                Operand::Target(target) => symbol(*target).unwrap_or_else(|| format!("0x{:04X}", target)),
+            })
+            .collect();
+
+        if operands.is_empty() {
+            self.inst.mnemonic().to_string()
+        } else {
+            format!("{} {}", self.inst.mnemonic(), operands.join(", "))
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render(&|_| None))
+    }
+}
+
+impl Line {
+    pub fn address(&self) -> u16 {
+        match self {
+            Line::Inst(instruction) => instruction.address,
+            Line::Bytes { address, .. } => *address,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Inst(instruction) => write!(f, "{}", instruction),
+            Line::Bytes { bytes, .. } => write!(f, ".byte {}", hex_list(bytes)),
+        }
+    }
+}
+
+fn hex_list(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect::<Vec<_>>().join(", ")
+}
+
+// Assembly source that `vm asm` turns back into the same memory contents.
+//
+// Text is decoded as instructions, data written as `.byte` and bss as `.zero`. Jump and call
+// targets get the image's symbol names or synthesized `L<address>` labels, jumps that can't be
+// written that way are kept as bytes. The trailing `exit` the assembler adds is left out, text
+// that doesn't end in one gets `.noexit` instead.
+pub fn disassemble(image: &Image) -> String {
+    const BYTES_PER_LINE: usize = 8;
+
+    let memory = image.memory();
+    let end = memory.len();
+    let mut sections: Vec<&Section> = image.sections.iter().collect();
+    sections.sort_by_key(|section| section.address);
+
+    let mut decoded: Vec<Vec<Line>> = sections.iter()
+        .map(|section| match section.kind {
+            SectionKind::Text => decode_all(&section.bytes, section.address),
+            _ => Vec::new(),
+        })
+        .collect();
+
+    // Labels can go before any instruction, anywhere in data and bss, and at the end
+    let mut boundaries: BTreeSet<usize> = decoded.iter().flatten().map(|line| line.address() as usize).collect();
+    for section in sections.iter().filter(|section| section.kind != SectionKind::Text) {
+        boundaries.extend(section.address as usize..section.address as usize + section.size);
+    }
+    boundaries.insert(end);
+
+    // The assembler adds the exit back at the end of the text
+    let mut implicit_exit = None;
+    let mut no_exit = false;
+    if let Some(lines) = sections.iter().rposition(|section| section.kind == SectionKind::Text).map(|i| &mut decoded[i]) {
+        if let Some(Line::Inst(Instruction { inst: Inst::Exit, address, .. })) = lines.last() {
+            implicit_exit = Some(*address as usize);
+            lines.pop();
+        } else {
+            no_exit = true;
+        }
+    }
+
+    let mut names: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+    for (name, address) in &image.symbols {
+        if boundaries.contains(&(*address as usize)) {
+            names.entry(*address).or_default().push(name.clone());
+        }
+    }
+    let mut name = |address: u16| names.entry(address).or_insert_with(|| vec![format!("L{:04X}", address)])[0].clone();
+
+    let entry = if image.entry != 0 || image.symbols.iter().any(|(name, _)| name == "main") {
+        Some(image.entry).filter(|entry| boundaries.contains(&(*entry as usize))).map(&mut name)
+    } else {
+        None
+    };
+
+    for line in decoded.iter_mut().flatten() {
+        if let Line::Inst(instruction) = line {
+            match instruction.target() {
+                Some(target) if boundaries.contains(&(target as usize)) && instruction.is_canonical() => {
+                    name(target);
+                }
+                Some(_) => {
+                    let start = instruction.address as usize;
+                    let bytes = memory[start..start + instruction.inst.len() as usize].to_vec();
+                    *line = Line::Bytes { address: instruction.address, bytes };
+                }
+                None => {}
+            }
+        }
+    }
+
+    let mut out = String::new();
+    if let Some(entry) = entry {
+        out += &format!(".entry {}\n", entry);
+    }
+    if no_exit {
+        out += ".noexit\n";
+    }
+
+    let mut emitted = BTreeSet::new();
+    let mut labels = |out: &mut String, address: usize| {
+        if let Some(names) = names.get(&(address as u16)).filter(|_| emitted.insert(address)) {
+            for name in names {
+                *out += &format!("{}:\n", name);
+            }
+        }
+    };
+    let mut kind = SectionKind::Text;
+    let mut pos = 0;
+
+    for (section, lines) in sections.iter().zip(&decoded) {
+        if section.kind != kind {
+            kind = section.kind;
+            out += match kind {
+                SectionKind::Text => ".text\n",
+                SectionKind::Data => ".data\n",
+                SectionKind::Bss => ".bss\n",
+            };
+        }
+        let start = section.address as usize;
+        if start > pos {
+            out += &format!("    .zero {}\n", start - pos);
+        }
+
+        if kind == SectionKind::Text {
+            for line in lines {
+                labels(&mut out, line.address() as usize);
+                let text = match line {
+                    Line::Inst(instruction) => instruction.render(&|address| names.get(&address).map(|names| names[0].clone())),
+                    Line::Bytes { .. } => line.to_string(),
+                };
+                out += &format!("    {:<24}; {:04X}\n", text, line.address());
+            }
+            // Labels on the dropped exit stay in the text
+            if let Some(address) = implicit_exit.filter(|address| (start..start + section.size).contains(address)) {
+                labels(&mut out, address);
+            }
+        } else {
+            // Runs of bytes are split at labels
+            let mut run = start;
+            while run < start + section.size {
+                labels(&mut out, run);
+                let len = (run + 1..start + section.size).find(|address| names.contains_key(&(*address as u16))).unwrap_or(start + section.size) - run;
+
+                if kind == SectionKind::Bss {
+                    out += &format!("    .zero {}\n", len);
+                } else {
+                    for chunk in memory[run..run + len].chunks(BYTES_PER_LINE) {
+                        out += &format!("    .byte {}\n", hex_list(chunk));
+                    }
+                }
+                run += len;
+            }
+        }
+        pos = start + section.size;
+    }
+    labels(&mut out, end);
+    out
+}
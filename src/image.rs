@@ -0,0 +1,178 @@
+use crate::object::{Reader, write_len, write_name, write_u16};
+use crate::vm::{MAX_MEMORY_SIZE, MIN_MEMORY_SIZE, STACK_RESERVE};
+
+// Loadable program, written by `vm asm` and `vm link`.
+//
+// All integers are big-endian, names are a u16 length followed by UTF-8 bytes.
+//
+// "VMIM" version:u8
+// entry:u16 memory_size:u32
+// section_count:u8 (kind:u8 address:u16 size:u32 bytes)*    bss sections have no bytes
+// symbol_count:u16 (address:u16 name)*                      may be empty
+pub const IMAGE_MAGIC: &[u8; 4] = b"VMIM";
+pub const IMAGE_VERSION: u8 = 1;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Image {
+    pub entry: u16,
+    // Smallest memory the program can run in, see `memory_size_for`
+    pub memory_size: usize,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<(String, u16)>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Section {
+    pub kind: SectionKind,
+    pub address: u16,
+    pub size: usize,
+    // Empty for bss
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SectionKind {
+    Text,
+    Data,
+    Bss,
+}
+
+impl SectionKind {
+    pub(crate) fn from_byte(byte: u8) -> Option<SectionKind> {
+        match byte {
+            0 => Some(SectionKind::Text),
+            1 => Some(SectionKind::Data),
+            2 => Some(SectionKind::Bss),
+            _ => None,
+        }
+    }
+}
+
+// Memory for sections that end at `end`, with room for the stack above them
+pub fn memory_size_for(end: usize) -> usize {
+    (end + STACK_RESERVE).clamp(MIN_MEMORY_SIZE, MAX_MEMORY_SIZE)
+}
+
+impl Image {
+    // A bare program, as produced before images existed
+    pub fn from_program(program: &[u8]) -> Image {
+        Image {
+            entry: 0,
+            memory_size: memory_size_for(program.len()),
+            sections: vec![Section { kind: SectionKind::Text, address: 0, size: program.len(), bytes: program.to_vec() }],
+            symbols: Vec::new(),
+        }
+    }
+
+    // Memory contents from address 0 to the end of the last section
+    pub fn memory(&self) -> Vec<u8> {
+        let end = self.sections.iter().map(|section| section.address as usize + section.size).max().unwrap_or(0);
+        let mut memory = vec![0; end];
+
+        for section in &self.sections {
+            let start = section.address as usize;
+            memory[start..start + section.bytes.len()].copy_from_slice(&section.bytes);
+        }
+        memory
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.memory_size < MIN_MEMORY_SIZE || self.memory_size > MAX_MEMORY_SIZE {
+            return Err(format!("Invalid memory size {}", self.memory_size));
+        }
+
+        let mut ranges = Vec::new();
+        for section in &self.sections {
+            let start = section.address as usize;
+            let end = start + section.size;
+
+            let expected = if section.kind == SectionKind::Bss { 0 } else { section.size };
+            if section.bytes.len() != expected {
+                return Err(format!("{:?} section at {:#06X} has {} bytes instead of {}", section.kind, start, section.bytes.len(), expected));
+            }
+            if end > self.memory_size {
+                return Err(format!("{:?} section at {:#06X} ends outside of {} bytes of memory", section.kind, start, self.memory_size));
+            }
+            if ranges.iter().any(|(other_start, other_end)| start < *other_end && *other_start < end) {
+                return Err(format!("{:?} section at {:#06X} overlaps another section", section.kind, start));
+            }
+            ranges.push((start, end));
+        }
+
+        let entry = self.entry as usize;
+        let in_text = self.sections.iter()
+            .any(|section| section.kind == SectionKind::Text && (section.address as usize..section.address as usize + section.size).contains(&entry));
+        if !in_text {
+            return Err(format!("Entry point {:#06X} is not in a text section", entry));
+        }
+        Ok(())
+    }
+
+    // Fails when the symbol table doesn't fit in its u16 lengths
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = IMAGE_MAGIC.to_vec();
+        bytes.push(IMAGE_VERSION);
+        write_u16(&mut bytes, self.entry as usize);
+        bytes.extend_from_slice(&(self.memory_size as u32).to_be_bytes());
+
+        bytes.push(self.sections.len() as u8);
+        for section in &self.sections {
+            bytes.push(section.kind as u8);
+            write_u16(&mut bytes, section.address as usize);
+            bytes.extend_from_slice(&(section.size as u32).to_be_bytes());
+            bytes.extend_from_slice(&section.bytes);
+        }
+
+        write_len(&mut bytes, self.symbols.len(), "Symbol count")?;
+        for (name, address) in &self.symbols {
+            write_u16(&mut bytes, *address as usize);
+            write_name(&mut bytes, name)?;
+        }
+        Ok(bytes)
+    }
+
+    pub fn is_image(bytes: &[u8]) -> bool {
+        bytes.starts_with(IMAGE_MAGIC)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Image, String> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        if reader.take(4)? != IMAGE_MAGIC {
+            return Err("Not a program image".to_string());
+        }
+        let version = reader.u8()?;
+        if version != IMAGE_VERSION {
+            return Err(format!("Unsupported image version {}, expected {}", version, IMAGE_VERSION));
+        }
+        let entry = reader.u16()?;
+        let memory_size = reader.u32()? as usize;
+
+        let mut sections = Vec::new();
+        for _ in 0..reader.u8()? {
+            let kind_byte = reader.u8()?;
+            let kind = SectionKind::from_byte(kind_byte).ok_or_else(|| format!("Unknown section kind {}", kind_byte))?;
+            let address = reader.u16()?;
+            let size = reader.u32()? as usize;
+            if size > MAX_MEMORY_SIZE {
+                return Err(format!("{:?} section of {} bytes is too large", kind, size));
+            }
+            let bytes = if kind == SectionKind::Bss { Vec::new() } else { reader.take(size)?.to_vec() };
+            sections.push(Section { kind, address, size, bytes });
+        }
+
+        let mut symbols = Vec::new();
+        for _ in 0..reader.u16()? {
+            let address = reader.u16()?;
+            symbols.push((reader.name()?, address));
+        }
+
+        if reader.pos != bytes.len() {
+            return Err("Trailing bytes after the image".to_string());
+        }
+
+        let image = Image { entry, memory_size, sections, symbols };
+        image.validate()?;
+        Ok(image)
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use crate::image::{Image, memory_size_for, Section, SectionKind};
+use crate::object::{Object, Symbol};
+use crate::vm::{Inst, MAX_MEMORY_SIZE};
+
+const SECTION_KINDS: [SectionKind; 3] = [SectionKind::Text, SectionKind::Data, SectionKind::Bss];
+
+// Places the text of all objects from address 0 followed by a single exit, then all their data
+// and then all their bss, and patches every relocation.
+// Execution starts at the object's `.entry` label, the global `main` label or else address 0.
+pub fn link(objects: &[Object]) -> Result<Image, Vec<String>> {
+    let mut errors = Vec::new();
+    // Address of every section of every object, indexed by `SectionKind`
+    let mut bases = vec![[0; 3]; objects.len()];
+    let mut starts = [0; 3];
+    let mut size = 0;
+
+    for kind in SECTION_KINDS {
+        starts[kind as usize] = size;
+        for (object, base) in objects.iter().zip(&mut bases) {
+            base[kind as usize] = size;
+            size += match kind {
+                SectionKind::Text => object.text_size,
+                SectionKind::Data => object.data_size(),
+                SectionKind::Bss => object.bss_size,
+            };
+        }
+        if kind == SectionKind::Text {
+            size += Inst::Exit.len() as usize;
+        }
+    }
+    if size > MAX_MEMORY_SIZE {
+        return Err(vec![format!("Program of {} bytes doesn't fit in the address space", size)]);
+    }
+
+    let address_of = |object: &Object, base: &[usize; 3], symbol: &Symbol| {
+        base[symbol.section as usize] + symbol.offset as usize - object.section_offset(symbol.section)
+    };
+
+    // Global symbol -> (address, defining object)
+    let mut globals: HashMap<&str, (usize, &str)> = HashMap::new();
+    for (object, base) in objects.iter().zip(&bases) {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            let address = address_of(object, base, symbol);
+
+            if let Some((_, other)) = globals.insert(&symbol.name, (address, &object.name)) {
+                errors.push(format!("Duplicate symbol {:?} in {} and {}", symbol.name, other, object.name));
+            }
+        }
+    }
+
+    let mut text = Vec::new();
+    let mut data = Vec::new();
+    for (object, base) in objects.iter().zip(&bases) {
+        let mut code = object.code.clone();
+
+        for relocation in &object.relocations {
+            // Local labels shadow globals from other objects
+            let local = object.symbols.iter()
+                .find(|symbol| symbol.name == relocation.symbol)
+                .map(|symbol| address_of(object, base, symbol));
+            let address = match local.or_else(|| globals.get(relocation.symbol.as_str()).map(|(address, _)| *address)) {
+                Some(address) => address as i32,
+                None => {
+                    errors.push(format!("Undefined symbol {:?} referenced in {}", relocation.symbol, object.name));
+                    continue;
+                }
+            };
+
+            let value = address + relocation.addend;
+            let offset = relocation.offset as usize;
+            if !(-32768..=65535).contains(&value) || offset + 2 > code.len() {
+                errors.push(format!("Relocation of {:?} at {:#06X} in {} is out of range", relocation.symbol, offset, object.name));
+                continue;
+            }
+            code[offset] = (value >> 8) as u8;
+            code[offset + 1] = value as u8;
+        }
+        data.extend_from_slice(&code[object.text_size..]);
+        text.extend(code.into_iter().take(object.text_size));
+    }
+    text.push(Inst::Exit as u8);
+
+    let mut entry = globals.get("main").map_or(0, |(address, _)| *address);
+    let mut entry_object: Option<&str> = None;
+    for (object, base) in objects.iter().zip(&bases) {
+        if let Some(label) = &object.entry {
+            if let Some(other) = entry_object {
+                errors.push(format!("Entry point defined in both {} and {}", other, object.name));
+            }
+            match object.symbols.iter().find(|symbol| symbol.name == *label) {
+                Some(symbol) => entry = address_of(object, base, symbol),
+                None => errors.push(format!("Entry label {:?} is not defined in {}", label, object.name)),
+            }
+            entry_object = Some(&object.name);
+        }
+    }
+
+    let mut symbols: Vec<(String, u16)> = globals.iter()
+        .map(|(name, (address, _))| (name.to_string(), *address as u16))
+        .collect();
+    symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+
+    let mut sections = vec![Section { kind: SectionKind::Text, address: 0, size: text.len(), bytes: text }];
+    if !data.is_empty() {
+        let address = starts[SectionKind::Data as usize] as u16;
+        sections.push(Section { kind: SectionKind::Data, address, size: data.len(), bytes: data });
+    }
+    let bss_start = starts[SectionKind::Bss as usize];
+    if size > bss_start {
+        sections.push(Section { kind: SectionKind::Bss, address: bss_start as u16, size: size - bss_start, bytes: Vec::new() });
+    }
+
+    let image = Image { entry: entry as u16, memory_size: memory_size_for(size), sections, symbols };
+    if let Err(error) = image.validate() {
+        errors.push(error);
+    }
+
+    if errors.is_empty() {
+        Ok(image)
+    } else {
+        Err(errors)
+    }
+}
@@ -1,39 +1,349 @@
 #![allow(dead_code)]
 // cargo watch -c -q -s 'cargo +nightly rustc -- -Awarnings -Zno-codegen && cargo test'
 
-use crate::assembler::{ParsedInst, Parser, print_tokens, read_all_tokens, Compiler};
-use crate::vm::VM;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::assembler::{assemble_debug, assemble_image, assemble_listing, assemble_object};
+use crate::debugger::Debugger;
+use crate::diagnostic::{Diagnostic, SourceFile};
+use crate::disassembler::{disassemble, REGISTER_NAMES};
+use crate::image::Image;
+use crate::linker::link;
+use crate::object::Object;
+use crate::trace::Trace;
+use crate::vm::{DEFAULT_MEMORY_SIZE, ExitReason, FaultKind, VM, VMConfig};
 
 mod vm;
 mod assembler;
+mod diagnostic;
+mod preprocessor;
+mod object;
+mod linker;
+mod image;
+mod disassembler;
+mod debugger;
+mod trace;
+
+// Process exit codes, faults are reported as EXIT_FAULT + fault number
+const EXIT_OK: i32 = 0;
+const EXIT_USAGE: i32 = 1;
+const EXIT_ASSEMBLER: i32 = 2;
+const EXIT_BUDGET: i32 = 3;
+const EXIT_LINKER: i32 = 4;
+const EXIT_FAULT: i32 = 10;
+
+// Instructions `vm debug` can step back by default
+const DEBUG_HISTORY: usize = 100_000;
+
+const USAGE: &str = "\
+Usage:
+  vm asm [-I <dir>]... [-c] [-s] [-l <file.lst>] <file.asm> [-o <file.bin|file.o>]
+  vm link [-s] <file.o>... [-o <file.bin>]
+  vm run [-I <dir>]... [-m <memory size>] [--fuel <instructions>] [--trap-div] [--trace <file>] <file.asm|file.bin>
+  vm replay <file.trace> [<step>]
+  vm disasm [-I <dir>]... <file.asm|file.bin>
+  vm debug [-I <dir>]... [-m <memory size>] [--history <instructions>] <file.asm|file.bin>
+
+-c writes an object for `vm link` instead of a program image, -s leaves out the symbol table,
+-l writes a listing with the address and encoding of every line.
+`vm disasm` prints assembly source that `vm asm` turns back into the same program.
+`vm debug` reads debugger commands from stdin, `help` lists them. --history sets how many
+instructions it can step back, 100000 by default.
+--trace records every instruction, as JSON lines if the file ends in `.jsonl`. `vm replay` shows
+the registers after the given step of a binary trace, or at its end.
+Files that aren't images or `.asm` sources are loaded as raw bytes at address 0.";
 
 fn main() {
-    let bytes = include_bytes!("../sample.asm");
-    let tokens = read_all_tokens(bytes);
-//    print_tokens(bytes, &tokens);
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let code = match args.first().map(|s| s.as_str()) {
+        Some("asm") => cmd_asm(&args[1..]),
+        Some("link") => cmd_link(&args[1..]),
+        Some("run") => cmd_run(&args[1..]),
+        Some("disasm") => cmd_disasm(&args[1..]),
+        Some("debug") => cmd_debug(&args[1..]),
+        Some("replay") => cmd_replay(&args[1..]),
+        _ => Err(usage_error()),
+    };
+
+    match code {
+        Ok(code) => process::exit(code),
+        Err((code, msg)) => {
+            eprintln!("{}", msg);
+            process::exit(code);
+        }
+    }
+}
+
+type CmdResult = Result<i32, (i32, String)>;
+
+fn usage_error() -> (i32, String) {
+    (EXIT_USAGE, USAGE.to_string())
+}
+
+fn cmd_asm(args: &[String]) -> CmdResult {
+    let mut input = None;
+    let mut output = None;
+    let mut include_paths = Vec::new();
+    let mut object = false;
+    let mut strip = false;
+    let mut listing = None;
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(iter.next().ok_or_else(usage_error)?)),
+            "-I" => include_paths.push(PathBuf::from(iter.next().ok_or_else(usage_error)?)),
+            "-c" => object = true,
+            "-s" => strip = true,
+            "-l" => listing = Some(PathBuf::from(iter.next().ok_or_else(usage_error)?)),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(usage_error()),
+        }
+    }
+
+    let input = input.ok_or_else(usage_error)?;
+    if let Some(listing) = listing {
+        let file = SourceFile::new(&input.display().to_string(), read_file(&input)?);
+        let (_, text) = assemble_listing(&file, &include_paths).map_err(assembler_error)?;
+        write_file(&listing, text.as_bytes())?;
+    }
 
-    let mut parser = Parser::new(bytes, tokens);
-    let mut compiler = Compiler::new();
+    let (output, bytes) = if object {
+        let file = SourceFile::new(&input.display().to_string(), read_file(&input)?);
+        let object = assemble_object(&file, &include_paths).map_err(assembler_error)?;
+        (output.unwrap_or_else(|| input.with_extension("o")), object.to_bytes())
+    } else {
+        let mut image = load_image(&input, &include_paths)?;
+        if strip {
+            image.symbols.clear();
+        }
+        (output.unwrap_or_else(|| input.with_extension("bin")), image.to_bytes())
+    };
 
-    let parsed = parser.parse().expect("Unable to parse");
+    write_file(&output, &bytes.map_err(|err| (EXIT_ASSEMBLER, err))?)
+}
 
-    let pre = compiler.compile(&parsed).expect("Unable to compiled");
+fn cmd_link(args: &[String]) -> CmdResult {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut strip = false;
+    let mut iter = args.iter();
 
-//    println!("{:#?}", pre);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(iter.next().ok_or_else(usage_error)?)),
+            "-s" => strip = true,
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+    if inputs.is_empty() {
+        return Err(usage_error());
+    }
 
-    let mut vm = VM::new();
+    let mut objects = Vec::new();
+    for input in &inputs {
+        let object = Object::from_bytes(&input.display().to_string(), &read_file(input)?)
+            .map_err(|err| (EXIT_USAGE, err))?;
+        objects.push(object);
+    }
 
-    for byte in pre {
-        vm.set(byte);
+    let mut image = link(&objects).map_err(|errors| (EXIT_LINKER, errors.join("\n")))?;
+    if strip {
+        image.symbols.clear();
     }
-    vm.reset();
+    let bytes = image.to_bytes().map_err(|err| (EXIT_LINKER, err))?;
+    write_file(&output.unwrap_or_else(|| inputs[0].with_extension("bin")), &bytes)
+}
+
+fn cmd_run(args: &[String]) -> CmdResult {
+    let mut input = None;
+    let mut config = VMConfig::new();
+    let mut memory_size = None;
+    let mut fuel = None;
+    let mut trace = None;
+    let mut include_paths = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-I" => include_paths.push(PathBuf::from(iter.next().ok_or_else(usage_error)?)),
+            "-m" => memory_size = Some(parse_number(iter.next())?),
+            "--fuel" => fuel = Some(parse_number(iter.next())? as u64),
+            "--trace" => trace = Some(PathBuf::from(iter.next().ok_or_else(usage_error)?)),
+            "--trap-div" => config = config.trap_division_by_zero(true),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(usage_error()),
+        }
+    }
+
+    let input = input.ok_or_else(usage_error)?;
+    let image = load_image(&input, &include_paths)?;
 
-    vm.run();
+    // Without -m the memory grows to what the image asks for
+    let memory_size = memory_size.unwrap_or_else(|| image.memory_size.max(DEFAULT_MEMORY_SIZE));
+    let mut vm = VM::with_config(config.memory_size(memory_size)).map_err(|err| (EXIT_USAGE, err))?;
+    vm.load_image(&image).map_err(|err| (EXIT_USAGE, err))?;
+    if trace.is_some() {
+        vm.start_trace();
+    }
+
+    let result = match fuel {
+        Some(fuel) => vm.run_for(fuel),
+        None => vm.run(),
+    };
+
+    // Written whatever the outcome, faults are what traces are for
+    if let (Some(path), Some(trace)) = (trace, vm.stop_trace()) {
+        if path.extension().is_some_and(|ext| ext == "jsonl") {
+            write_file(&path, trace.to_json_lines().as_bytes())?;
+        } else {
+            write_file(&path, &trace.to_bytes())?;
+        }
+    }
+
+    match result {
+        Ok(ExitReason::Exit) => Ok(EXIT_OK),
+        Ok(ExitReason::BudgetExhausted) => Err((EXIT_BUDGET, "Instruction budget exhausted".to_string())),
+        Ok(ExitReason::Watchpoint(_)) => unreachable!("No watchpoints are set"),
+        Err(fault) => Err((fault_exit_code(fault.kind), fault.to_string())),
+    }
+}
+
+fn cmd_disasm(args: &[String]) -> CmdResult {
+    let mut input = None;
+    let mut include_paths = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-I" => include_paths.push(PathBuf::from(iter.next().ok_or_else(usage_error)?)),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(usage_error()),
+        }
+    }
+
+    let input = input.ok_or_else(usage_error)?;
+    let image = load_image(&input, &include_paths)?;
+    print!("{}", disassemble(&image));
+    Ok(EXIT_OK)
+}
+
+fn cmd_debug(args: &[String]) -> CmdResult {
+    let mut input = None;
+    let mut memory_size = None;
+    let mut history = DEBUG_HISTORY;
+    let mut include_paths = Vec::new();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-I" => include_paths.push(PathBuf::from(iter.next().ok_or_else(usage_error)?)),
+            "-m" => memory_size = Some(parse_number(iter.next())?),
+            "--history" => history = parse_number(iter.next())?,
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(usage_error()),
+        }
+    }
+
+    // Sources also give line numbers, images only their symbols
+    let input = input.ok_or_else(usage_error)?;
+    let (image, lines) = if input.extension().is_some_and(|ext| ext == "asm") {
+        let file = SourceFile::new(&input.display().to_string(), read_file(&input)?);
+        assemble_debug(&file, &include_paths).map_err(assembler_error)?
+    } else {
+        (load_image(&input, &include_paths)?, Vec::new())
+    };
+
+    let memory_size = memory_size.unwrap_or_else(|| image.memory_size.max(DEFAULT_MEMORY_SIZE));
+    let config = VMConfig::new().memory_size(memory_size).history_size(history);
+    let mut vm = VM::with_config(config).map_err(|err| (EXIT_USAGE, err))?;
+    vm.load_image(&image).map_err(|err| (EXIT_USAGE, err))?;
+
+    let mut debugger = Debugger::new(vm, image.symbols.clone(), lines);
+    debugger.repl(io::stdin().lock(), io::stdout()).map_err(|err| (EXIT_USAGE, err.to_string()))?;
+    Ok(EXIT_OK)
+}
+
+fn cmd_replay(args: &[String]) -> CmdResult {
+    let (input, step) = match args {
+        [input] => (input, None),
+        [input, step] => (input, Some(parse_number(Some(step))?)),
+        _ => return Err(usage_error()),
+    };
+
+    let input = PathBuf::from(input);
+    let trace = Trace::from_bytes(&read_file(&input)?)
+        .map_err(|err| (EXIT_USAGE, format!("Invalid trace {}: {}", input.display(), err)))?;
+    let step = step.unwrap_or(trace.entries.len());
+    let state = trace.state_at(step).map_err(|err| (EXIT_USAGE, err))?;
+
+    println!("step {} of {}", step, trace.entries.len());
+    println!("pc  0x{:04X}", state.pc);
+    for (name, value) in REGISTER_NAMES.iter().zip(&state.registers) {
+        println!("{:<3} 0x{:04X} {:>6}", name, value, *value as i16);
+    }
+    Ok(EXIT_OK)
+}
+
+fn fault_exit_code(kind: FaultKind) -> i32 {
+    EXIT_FAULT + match kind {
+        FaultKind::InvalidOpcode(_) => 0,
+        FaultKind::InvalidRegister(_) => 1,
+        FaultKind::OutOfBounds(_) => 2,
+        FaultKind::StackOverflow => 3,
+        FaultKind::StackUnderflow => 4,
+        FaultKind::DivisionByZero => 5,
+    }
+}
+
+fn parse_number(arg: Option<&String>) -> Result<usize, (i32, String)> {
+    let arg = arg.ok_or_else(usage_error)?;
+    arg.parse::<usize>().map_err(|_| (EXIT_USAGE, format!("Expected a number, found {:?}", arg)))
+}
+
+// `.asm` files are assembled, other files are images or raw programs
+fn load_image(path: &Path, include_paths: &[PathBuf]) -> Result<Image, (i32, String)> {
+    let bytes = read_file(path)?;
+
+    if path.extension().is_some_and(|ext| ext == "asm") {
+        let file = SourceFile::new(&path.display().to_string(), bytes);
+        assemble_image(&file, include_paths).map_err(assembler_error)
+    } else if Image::is_image(&bytes) {
+        Image::from_bytes(&bytes).map_err(|err| (EXIT_USAGE, format!("Invalid image {}: {}", path.display(), err)))
+    } else {
+        Ok(Image::from_program(&bytes))
+    }
+}
+
+fn assembler_error(errors: Vec<Diagnostic>) -> (i32, String) {
+    let rendered: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    (EXIT_ASSEMBLER, rendered.join("\n\n"))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, (i32, String)> {
+    fs::read(path).map_err(|err| (EXIT_USAGE, format!("Unable to read {}: {}", path.display(), err)))
+}
+
+fn write_file(path: &Path, bytes: &[u8]) -> CmdResult {
+    fs::write(path, bytes).map_err(|err| (EXIT_USAGE, format!("Unable to write {}: {}", path.display(), err)))?;
+    Ok(EXIT_OK)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::assembler::Compiler;
+    use crate::assembler::{assemble, assemble_debug, assemble_image, assemble_listing, assemble_object, assemble_with_includes, Compiler, Parser, print_tokens, read_all_tokens, SourceLine};
+    use crate::diagnostic::{Origin, Severity};
+    use crate::disassembler::{decode, Operand};
+    use crate::image::{IMAGE_VERSION, Section, SectionKind};
+    use crate::object::Symbol;
+    use crate::preprocessor::{MAX_MACRO_EXPANSIONS, preprocess};
+    use crate::trace::Trace;
+    use crate::vm::{AccessKind, ExitReason, Fault, FaultKind, Inst, MAX_MEMORY_SIZE, MemoryAccess, STACK_RESERVE, Step, VMConfig, WatchHit, WatchKind};
 
     use super::*;
 
@@ -47,25 +357,1114 @@ mod tests {
 
     #[test]
     fn test_parser() {
-        let bytes = include_bytes!("../sample.asm");
-        let tokens = read_all_tokens(bytes);
-        let mut parser = Parser::new(bytes, tokens);
+        let file = SourceFile::new("sample.asm", include_bytes!("../sample.asm").to_vec());
+        let tokens = read_all_tokens(&file.text);
+        let mut parser = Parser::new(&file, tokens);
 
         match parser.parse() {
             Ok(ok) => { println!("{:#?}", ok) }
-            Err(err) => { panic!("{}", err) }
+            Err(err) => { panic!("{:#?}", err) }
         };
     }
 
     #[test]
     fn test_compiler() {
-        let bytes = include_bytes!("../sample.asm");
-        let tokens = read_all_tokens(bytes);
-        let mut parser = Parser::new(bytes, tokens);
+        let file = SourceFile::new("sample.asm", include_bytes!("../sample.asm").to_vec());
+        let tokens = read_all_tokens(&file.text);
+        let mut parser = Parser::new(&file, tokens);
 
         let mut compiler = Compiler::new();
         let parsed = parser.parse().expect("Unable to parse");
 
-        compiler.precompile(&parsed).expect("Unable to compiled");
+        compiler.precompile(&file, &parsed).expect("Unable to compiled");
+    }
+
+    fn run_source(source: &[u8]) -> VM {
+        let file = SourceFile::new("test.asm", source.to_vec());
+        let image = assemble_image(&file, &[]).expect("Unable to assemble");
+
+        let mut vm = VM::new();
+        vm.load_image(&image).unwrap();
+        vm.run().expect("Program faulted");
+        vm
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let file = SourceFile::new("test.asm", b"set a, 1\nadd a, x\nfoo\n  jmp nowhere\n".to_vec());
+        let tokens = read_all_tokens(&file.text);
+        let errors = Parser::new(&file, tokens).parse().unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].line, errors[0].column), (2, 8));
+        assert_eq!(errors[0].message, "Expected register name, found \"x\"");
+        assert_eq!((errors[1].line, errors[1].column), (3, 1));
+
+        let file = SourceFile::new("test.asm", b"set a, 1\n  jmp nowhere\n".to_vec());
+        let errors = assemble(&file).unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].severity, Severity::Error);
+        assert_eq!(errors[0].to_string(), "\
+error: Jump to invalid label: \"nowhere\"
+ --> test.asm:2:3
+  |
+2 |   jmp nowhere
+  |   ^^^^^^^^^^^");
+    }
+
+    #[test]
+    fn test_load_store() {
+        let vm = run_source(b"set a, 200\nset b, 1000\nstw b, a, 2\nldw c, a, 2\nstb b, a\nldb d, a\n");
+
+        assert_eq!(vm.register(3), 1000);
+        assert_eq!(vm.register(4), 1000 & 0xFF);
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let vm = run_source(b"set a, 50000\nset b, 4\nmov c, a\nshr c, b\nmov d, a\nsar d, b\nmov e, a\nshl e, b\nmov f, a\nnot f\nmov g, a\nset h, 255\nand g, h\nxor h, b\n");
+
+        assert_eq!(vm.register(3), 50000 >> 4);
+        assert_eq!(vm.register(4), ((50000u16 as i16) >> 4) as u16);
+        assert_eq!(vm.register(5), 50000u16 << 4);
+        assert_eq!(vm.register(6), !50000u16);
+        assert_eq!(vm.register(7), 50000 & 255);
+        assert_eq!(vm.register(8), 255 ^ 4);
+    }
+
+    fn run_bytes(bytes: &[u8]) -> Result<ExitReason, Fault> {
+        let mut vm = VM::with_config(VMConfig::new().trap_division_by_zero(true)).unwrap();
+        for byte in bytes {
+            vm.set(*byte);
+        }
+        vm.reset();
+        vm.run()
+    }
+
+    #[test]
+    fn test_faults() {
+        let invalid_opcode = run_bytes(&[Inst::Nop as u8, 200]);
+        assert_eq!(invalid_opcode, Err(Fault { pc: 1, kind: FaultKind::InvalidOpcode(200) }));
+
+        let invalid_register = run_bytes(&[Inst::Mov as u8, 1, 16]);
+        assert_eq!(invalid_register, Err(Fault { pc: 0, kind: FaultKind::InvalidRegister(16) }));
+
+        let out_of_bounds = run_bytes(&[Inst::SetShort as u8, 1, 0x03, 0xFF, Inst::LoadWord as u8, 2, 1, 0]);
+        assert_eq!(out_of_bounds, Err(Fault { pc: 4, kind: FaultKind::OutOfBounds(1024) }));
+
+        let underflow = run_bytes(&[Inst::Pop as u8, 1]);
+        assert_eq!(underflow, Err(Fault { pc: 0, kind: FaultKind::StackUnderflow }));
+
+        let overflow = run_bytes(&[Inst::SetByte as u8, 15, 1, Inst::Push as u8, 1]);
+        assert_eq!(overflow, Err(Fault { pc: 3, kind: FaultKind::StackOverflow }));
+
+        // The stack stops above the program instead of overwriting it
+        let program = [Inst::Push as u8, 1, Inst::JumpBw as u8, 4];
+        let mut vm = VM::with_config(VMConfig::new().memory_size(64)).unwrap();
+        vm.load(&program).unwrap();
+        assert_eq!(vm.run(), Err(Fault { pc: 0, kind: FaultKind::StackOverflow }));
+        assert_eq!(vm.register(15), 2);
+        assert_eq!(&vm.memory()[..4], program);
+
+        let division = run_bytes(&[Inst::Div as u8, 1, 2]);
+        assert_eq!(division, Err(Fault { pc: 0, kind: FaultKind::DivisionByZero }));
+
+        assert_eq!(run_bytes(&[Inst::Exit as u8]), Ok(ExitReason::Exit));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = VM::new();
+        for byte in &[Inst::SetByte as u8, 1, 7, Inst::Exit as u8] {
+            vm.set(*byte);
+        }
+        vm.reset();
+
+        assert_eq!(vm.step(), Ok(Step::Executed(Inst::SetByte)));
+        assert_eq!(vm.register(1), 7);
+        assert_eq!(vm.step(), Ok(Step::Exited));
+        assert_eq!(vm.step(), Ok(Step::Exited));
+    }
+
+    #[test]
+    fn test_run_for() {
+        let mut vm = VM::new();
+        for byte in &[Inst::Nop as u8, Inst::JumpBw as u8, 3] {
+            vm.set(*byte);
+        }
+        vm.reset();
+
+        assert_eq!(vm.run_for(1000), Ok(ExitReason::BudgetExhausted));
+        assert_eq!(vm.run_for(1000), Ok(ExitReason::BudgetExhausted));
+    }
+
+    #[test]
+    fn test_memory_size() {
+        assert!(VM::with_config(VMConfig::new().memory_size(MAX_MEMORY_SIZE + 1)).is_err());
+        assert!(VM::with_config(VMConfig::new().memory_size(0)).is_err());
+
+        let mut vm = VM::with_config(VMConfig::new().memory_size(MAX_MEMORY_SIZE)).unwrap();
+        // set a, 0xFFFE; set b, 0x1234; stw b, a; ldb c, a, 1; push b; pop d; exit
+        let program = [
+            Inst::SetShort as u8, 1, 0xFF, 0xFE,
+            Inst::SetShort as u8, 2, 0x12, 0x34,
+            Inst::StoreWord as u8, 2, 1, 0,
+            Inst::LoadByte as u8, 3, 1, 1,
+            Inst::Push as u8, 2,
+            Inst::Pop as u8, 4,
+            Inst::Exit as u8,
+        ];
+        for byte in &program {
+            vm.set(*byte);
+        }
+        vm.reset();
+
+        assert_eq!(vm.register(15), 0xFFFE);
+        assert_eq!(vm.run(), Ok(ExitReason::Exit));
+        assert_eq!(vm.register(3), 0x34);
+        assert_eq!(vm.register(4), 0x1234);
+    }
+
+    #[test]
+    fn test_cli() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(fault_exit_code(FaultKind::InvalidOpcode(200)), 10);
+        assert_eq!(fault_exit_code(FaultKind::OutOfBounds(1024)), 12);
+        assert_eq!(fault_exit_code(FaultKind::DivisionByZero), 15);
+
+        assert_eq!(cmd_run(&args(&["-m"])), Err(usage_error()));
+        assert_eq!(cmd_run(&args(&["a.bin", "b.bin"])), Err(usage_error()));
+        assert_eq!(cmd_run(&args(&[])), Err(usage_error()));
+        assert_eq!(cmd_run(&args(&["-m", "lots", "a.bin"])), Err((EXIT_USAGE, "Expected a number, found \"lots\"".to_string())));
+        assert_eq!(cmd_asm(&args(&["a.asm", "-o"])), Err(usage_error()));
+        assert_eq!(cmd_link(&args(&["-s"])), Err(usage_error()));
+
+        let dir = env::temp_dir().join(format!("vm_cli_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, bytes: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, bytes).unwrap();
+            path.display().to_string()
+        };
+
+        let underflow = write("underflow.bin", &[Inst::Pop as u8, 1]);
+        assert_eq!(cmd_run(&args(&[&underflow])).unwrap_err().0, EXIT_FAULT + 4);
+        let forever = write("forever.bin", &[Inst::JumpBw as u8, 2]);
+        assert_eq!(cmd_run(&args(&["--fuel", "100", &forever])).unwrap_err().0, EXIT_BUDGET);
+        assert_eq!(cmd_run(&args(&["-m", "1", &forever])).unwrap_err().0, EXIT_USAGE);
+        let bad = write("bad.asm", b"add a, 1\n");
+        assert_eq!(cmd_run(&args(&[&bad])).unwrap_err().0, EXIT_ASSEMBLER);
+        let object = write("lib.o", &assemble_object(&SourceFile::new("lib.asm", b"call missing\n".to_vec()), &[]).unwrap().to_bytes().unwrap());
+        assert_eq!(cmd_link(&args(&[&object])).unwrap_err().0, EXIT_LINKER);
+        assert_eq!(cmd_run(&args(&[&dir.join("missing.bin").display().to_string()])).unwrap_err().0, EXIT_USAGE);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const IF_MACRO: &str = "\
+!macro if(cond, ifTrue, ifFalse):
+${cond}
+else
+jmp ${macro_id}_else
+${ifTrue}
+jmp ${macro_id}_end
+${macro_id}_else:
+${ifFalse}
+${macro_id}_end:
+!endmacro
+";
+
+    #[test]
+    fn test_macros() {
+        let source = IF_MACRO.to_string() + "\
+!macro max(dst, x, y)
+!if((gt ${x}, ${y}), (mov ${dst}, ${x}), (mov ${dst}, ${y}))
+!endmacro
+
+set a, 5
+!max(c, a, b)
+!if(then, nop, (set e, 1)) ; comment
+";
+        let expanded = preprocess(&SourceFile::new("test.asm", source.into_bytes()), &[]).unwrap();
+
+        assert_eq!(String::from_utf8(expanded.text).unwrap(), "
+set a, 5
+gt a, b
+else
+jmp __if_2_else
+mov c, a
+jmp __if_2_end
+__if_2_else:
+mov c, b
+__if_2_end:
+then
+else
+jmp __if_3_else
+nop
+jmp __if_3_end
+__if_3_else:
+set e, 1
+__if_3_end:
+");
+        assert_eq!(expanded.origins[1], Origin { file: "test.asm".to_string(), line: 15, expansion: None });
+        assert_eq!(expanded.origins[2], Origin { file: "test.asm".to_string(), line: 16, expansion: Some("max".to_string()) });
+        assert_eq!(expanded.origins[10].line, 17);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let source = IF_MACRO.to_string() + "\
+!macro forever()
+!forever
+!endmacro
+
+nop
+!if(nop, nop)
+!forever()
+!missing
+";
+        let errors = assemble(&SourceFile::new("test.asm", source.into_bytes())).unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|err| err.line).collect();
+
+        assert_eq!(lines, vec![16, 17, 18]);
+        assert!(errors[0].message.contains("expects 3 arguments"));
+        assert!(errors[1].message.contains("deeper than"));
+        assert!(errors[2].message.contains("Unknown macro"));
+
+        // Names can start with `macro`
+        let vm = run_source(b"!macro macrox(r)\nset ${r}, 4\n!endmacro\n!macrox(a)\nset b, 5\n");
+        assert_eq!((vm.register(1), vm.register(2)), (4, 5));
+
+        // Doubling at every level stops at the first path that gets too deep
+        let source = b"!macro twice()\n!twice\n!twice\n!endmacro\n!twice\n";
+        let errors = assemble(&SourceFile::new("test.asm", source.to_vec())).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("deeper than"));
+
+        // 2^20 expansions that all stay shallow
+        let mut source = "!macro level20()\nnop\n!endmacro\n".to_string();
+        for level in 0..20 {
+            source += &format!("!macro level{}()\n!level{}\n!level{}\n!endmacro\n", level, level + 1, level + 1);
+        }
+        source += "!level0\n";
+        let errors = assemble(&SourceFile::new("test.asm", source.into_bytes())).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, format!("More than {} macro expansions", MAX_MACRO_EXPANSIONS));
+        assert_eq!(errors[0].line, 84);
+    }
+
+    #[test]
+    fn test_functions() {
+        let source = b"\
+set c, 7
+set d, 8
+set e, 3
+set f, 4
+call sum(e, f) use (c, d)
+mov g, a
+set a, 10
+set b, 20
+call sum(b, a)
+exit
+
+fun sum($a, $b) use ($c, $d) {
+    set $c, 0
+    set $d, 1
+${fun}_loop:
+    add $c, $b
+    sub $a, $d
+    neq $a, z
+    then
+    jmp ${fun}_loop
+    mov $a, $c
+}
+";
+        let vm = run_source(source);
+
+        assert_eq!(vm.register(7), 12);
+        assert_eq!(vm.register(1), 200);
+        assert_eq!((vm.register(3), vm.register(4)), (7, 8));
+        assert_eq!(vm.register(15), 1022);
+
+        // `ret` is a single jump to the epilogue, so it can be conditional
+        let source = b"\
+set c, 9
+call early(a) use (c)
+mov b, a
+set a, 1
+call early(a) use (c)
+exit
+
+fun early($a) use ($c) {
+    set $c, 5
+    eq $a, z
+    then
+    ret
+    set $a, 42
+}
+";
+        let vm = run_source(source);
+        assert_eq!((vm.register(1), vm.register(2), vm.register(3)), (42, 0, 9));
+        assert_eq!(vm.register(15), 1022);
+
+        let source = b"then\ncall early(a)\nelse\ncall early(b)\n\nfun early($a) {\n}\n";
+        let errors = assemble(&SourceFile::new("test.asm", source.to_vec())).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 4);
+        assert!(errors[0].message.contains("can't follow else"));
+    }
+
+    #[test]
+    fn test_function_errors() {
+        let source = b"\
+call sum(a)
+call sum(a, b) use (c)
+call nothing(a)
+call sum
+call none
+
+fun sum($a, $b) use ($c, $d) {
+    add $a, $e
+}
+fun none() {
+}
+";
+        let errors = assemble(&SourceFile::new("test.asm", source.to_vec())).unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|err| err.line).collect();
+
+        assert_eq!(lines, vec![1, 2, 3, 4, 8]);
+        assert!(errors[0].message.contains("expects 2 arguments"));
+        assert_eq!(errors[3].message, "Function \"sum\" expects 2 arguments but the call has no argument list");
+        assert!(errors[4].message.contains("$e"));
+    }
+
+    #[test]
+    fn test_jumps() {
+        let nops = "nop\n".repeat(300);
+        let source = format!("\
+set a, 1
+jmp short_fw
+set a, 2
+short_fw:
+jmp long_fw
+{nops}
+long_bw:
+set b, 3
+jmp end
+long_fw:
+set c, 4
+jmp long_bw
+{nops}
+end:
+", nops = nops);
+        let program = assemble(&SourceFile::new("test.asm", source.clone().into_bytes())).unwrap();
+
+        assert_eq!(program[3], Inst::JumpFw as u8);
+        assert_eq!(program[4], 3);
+        assert_eq!(program[8], Inst::Jump as u8);
+
+        let vm = run_source(source.as_bytes());
+        assert_eq!((vm.register(1), vm.register(2), vm.register(3)), (1, 3, 4));
+
+        let vm = run_source((IF_MACRO.to_string() + "set a, 1\n!if((eq a, z), (set b, 1), (set b, 2))\n").as_bytes());
+        assert_eq!(vm.register(2), 2);
+    }
+
+    #[test]
+    fn test_data_directives() {
+        let source = b"\
+call code
+table:
+.byte 1, 2, 255
+.word 4660, 65535
+msg:
+.string \"hi;\\n\\\"\" ; comment
+.zero 3
+code:
+exit
+";
+        let program = assemble(&SourceFile::new("test.asm", source.to_vec())).unwrap();
+
+        assert_eq!(&program[..3], &[Inst::Call as u8, 0, 19]);
+        assert_eq!(&program[3..19], &[1, 2, 255, 0x12, 0x34, 0xFF, 0xFF, b'h', b'i', b';', b'\n', b'"', 0, 0, 0, 0]);
+        assert_eq!(program[19], Inst::Exit as u8);
+
+        let errors = assemble(&SourceFile::new("test.asm", b".byte 256\n.string \"abc\n.foo 1\n".to_vec())).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].message.contains("out of range"));
+        assert!(errors[1].message.contains("Unterminated"));
+        assert!(errors[2].message.contains("Unknown directive"));
+    }
+
+    #[test]
+    fn test_load_address() {
+        let source = b"\
+la a, msg
+ldb b, a, 1
+set c, table
+ldw d, c
+jmp end
+msg:
+.string \"hi\"
+table:
+.word msg, end
+end:
+";
+        let vm = run_source(source);
+
+        assert_eq!(vm.register(1), 18);
+        assert_eq!(vm.register(2), b'i' as u16);
+        assert_eq!(vm.register(4), 18);
+
+        let errors = assemble(&SourceFile::new("test.asm", b"la a, nowhere\nldb b, a, far\n.zero 300\nfar:\n".to_vec())).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].message.contains("undefined symbol"));
+        assert!(errors[1].message.contains("out of range"));
+    }
+
+    #[test]
+    fn test_expressions() {
+        let source = b"\
+.equ count, 3
+.equ size, count * 2 + 1
+set a, (size << 4) | ~-1
+set b, end - start
+set c, -size % 4 + 100 / 7
+la d, start + size
+exit
+start:
+.zero size & 6
+.byte count, end - start
+end:
+";
+        let program = assemble(&SourceFile::new("test.asm", source.to_vec())).unwrap();
+        assert_eq!(&program[..3], &[Inst::SetByte as u8, 1, 112]);
+
+        let vm = run_source(source);
+        assert_eq!(vm.register(1), 112);
+        assert_eq!(vm.register(2), 8);
+        assert_eq!(vm.register(3), 11);
+        assert_eq!(vm.register(4), 22);
+
+        let errors = assemble(&SourceFile::new("test.asm", b".equ big, 65536 * 32768\nset a, 1 / (2 - 2)\n".to_vec())).unwrap_err();
+        assert_eq!(errors[0].message, "Arithmetic overflow in expression");
+        assert_eq!(errors[1].message, "Division by zero in expression");
+        assert_eq!(errors[1].column, 8);
+
+        let errors = assemble(&SourceFile::new("test.asm", b".equ loop, loop + 1\nset b, loop\n".to_vec())).unwrap_err();
+        assert_eq!(errors[0].message, "Constant \"loop\" is defined in terms of itself");
+        let errors = assemble(&SourceFile::new("test.asm", b".equ x, y + 1\n.equ y, z * 2\n.equ z, x\nset a, 1\nset b, y\n".to_vec())).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Constant \"y\" is defined in terms of itself");
+
+        // Long chains aren't cycles, in images and in objects
+        let mut source = ".equ c0, 1\n.equ p0, target\n".to_string();
+        for i in 1..40 {
+            source += &format!(".equ c{}, c{} + 1\n.equ p{}, p{} + 1\n", i, i - 1, i, i - 1);
+        }
+        source += "set a, c39\nla b, p39 - 39\ntarget:\n";
+        let vm = run_source(source.as_bytes());
+        assert_eq!((vm.register(1), vm.register(2)), (40, 7));
+        let object = assemble_object(&SourceFile::new("test.asm", source.into_bytes()), &[]).unwrap();
+        assert_eq!(object.relocations[0].addend, 0);
+
+        let errors = assemble(&SourceFile::new("test.asm", b".zero later\nlater:\n".to_vec())).unwrap_err();
+        assert_eq!(errors[0].message, "Expected a constant, found \"later\"");
+    }
+
+    #[test]
+    fn test_literals() {
+        let source = b"\
+set a, 0x1F
+set b, 0b1010_0101
+set c, -1
+set d, 'A'
+set e, ';' ; comment
+set f, '\\n' + 0xFFFF - 0xFFFF
+exit
+.byte -1, 'z', 0xff
+";
+        let program = assemble(&SourceFile::new("test.asm", source.to_vec())).unwrap();
+        assert_eq!(&program[..3], &[Inst::SetByte as u8, 1, 0x1F]);
+        assert_eq!(&program[6..10], &[Inst::SetShort as u8, 3, 0xFF, 0xFF]);
+        assert_eq!(&program[20..23], &[0xFF, b'z', 0xFF]);
+
+        let vm = run_source(source);
+        let registers: Vec<u16> = (1..=6).map(|i| vm.register(i)).collect();
+        assert_eq!(registers, [0x1F, 0xA5, 0xFFFF, 65, 59, 10]);
+
+        let errors = assemble(&SourceFile::new("test.asm", b"set a, 0x\nset a, 12ab\nset a, 'ab'\n.byte -129\nset a, -32769\n".to_vec())).unwrap_err();
+        assert_eq!(errors.len(), 5);
+        assert!(errors[0].message.starts_with("Unable to parse integer \"0x\""));
+        assert!(errors[2].message.contains("single byte"));
+        assert_eq!(errors[3].message, "Value out of range (-128 to 255): -129");
+        assert_eq!(errors[4].message, "Value out of range (-32768 to 65535): -32769");
+    }
+
+    #[test]
+    fn test_lexer() {
+        let source = b".equ Max_2, 3\r\n\tset $0, 0\r\nLoop2:\r\n\tset $1, 1\r\n\tadd a, b\r\n\tset c, Max_2\r\n\tlt a, c\r\n\tthen\r\n\tjmp Loop2\r\n";
+        let vm = run_source(source);
+        assert_eq!(vm.register(1), 3);
+
+        let file = SourceFile::new("test.asm", b"set a, 1 @\nset b, 2\nadd a, b # c\n".to_vec());
+        let errors = assemble(&file).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Unexpected character \"@\"");
+        assert_eq!((errors[0].line, errors[0].column), (1, 10));
+        assert_eq!((errors[1].line, errors[1].column), (3, 10));
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = env::temp_dir().join(format!("vm_includes_{}", process::id()));
+        let write = |name: &str, text: &str| {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        };
+
+        write("main.asm", ".include \"lib/util.asm\"\n.include \"common.asm\"\nset a, answer\n!double(a)\n");
+        write("lib/util.asm", ".include \"common.asm\" ; from the include path\n");
+        write("inc/common.asm", ".equ answer, 21\n!macro double(reg)\nadd ${reg}, ${reg}\n!endmacro\n");
+        write("cycle.asm", ".include \"lib/cycle.asm\"\n");
+        write("lib/cycle.asm", "nop\n.include \"../cycle.asm\"\n");
+        write("bad.asm", "nop\n.include \"lib/bad.asm\"\n");
+        write("missing.asm", "nop\n.include \"lib/missing.asm\"\n");
+        write("lib/bad.asm", "\n  add a, 1\n");
+
+        let includes = [dir.join("inc")];
+        let load = |name: &str| {
+            let path = dir.join(name);
+            let file = SourceFile::new(&path.display().to_string(), fs::read(&path).unwrap());
+            assemble_with_includes(&file, &includes)
+        };
+
+        let mut vm = VM::new();
+        vm.load(&load("main.asm").unwrap()).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.register(1), 42);
+
+        let errors = load("cycle.asm").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.starts_with("Include cycle: "));
+        assert!(errors[0].file.ends_with("cycle.asm"));
+        assert_eq!(errors[0].line, 2);
+
+        let errors = load("missing.asm").unwrap_err();
+        assert_eq!(errors[0].message, "Unable to find included file \"lib/missing.asm\"");
+        assert_eq!(errors[0].line, 2);
+
+        let errors = load("bad.asm").unwrap_err();
+        assert!(errors[0].file.ends_with("lib/bad.asm"));
+        assert_eq!((errors[0].line, errors[0].column), (2, 10));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_linker() {
+        let object = |name: &str, source: &str| {
+            let object = assemble_object(&SourceFile::new(name, source.as_bytes().to_vec()), &[]).unwrap();
+            Object::from_bytes(name, &object.to_bytes().unwrap()).unwrap()
+        };
+
+        let main = object("main.asm", "\
+.global back
+set a, 5
+call double
+la b, table + 2
+ldw c, b
+set e, end - back
+jmp done
+back:
+exit
+end:
+");
+        let lib = object("lib.asm", "\
+.global double, table, done
+double:
+add a, a
+ret
+table:
+.word 1, 2, 3
+done:
+set d, 7
+jmp back
+");
+        assert_eq!(main.imports, ["done", "double", "table"]);
+        assert_eq!(main.relocations.len(), 3);
+        assert_eq!(lib.imports, ["back"]);
+
+        let image = link(&[main.clone(), lib.clone()]).unwrap();
+        assert_eq!(image.symbols[0], ("back".to_string(), 21));
+        let mut vm = VM::new();
+        vm.load_image(&image).unwrap();
+        vm.run().unwrap();
+        let registers: Vec<u16> = (1..=5).map(|i| vm.register(i)).collect();
+        assert_eq!(registers, [10, 28, 2, 7, 1]);
+
+        let errors = link(&[main.clone(), lib.clone(), object("dup.asm", ".global double\ndouble:\nret\n")]).unwrap_err();
+        assert_eq!(errors, ["Duplicate symbol \"double\" in lib.asm and dup.asm"]);
+
+        let errors = link(&[main]).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0], "Undefined symbol \"double\" referenced in main.asm");
+
+        let errors = assemble_object(&SourceFile::new("bad.asm", b".byte here\nset a, here * 2\nhere:\n".to_vec()), &[]).unwrap_err();
+        let messages: Vec<&str> = errors.iter().map(|err| err.message.as_str()).collect();
+        assert_eq!(messages, [
+            "Address of \"here\" doesn't fit in a byte",
+            "Address of \"here\" can't be relocated in this expression",
+        ]);
+
+        let errors = assemble_object(&SourceFile::new("bad.asm", b"start:\nset a, value - start\n.data\nvalue:\n.byte 1\n".to_vec()), &[]).unwrap_err();
+        assert_eq!(errors[0].message, "Address of \"start\" can't be relocated in this expression");
+
+        let errors = assemble_object(&SourceFile::new("bad.asm", b".global nowhere\n".to_vec()), &[]).unwrap_err();
+        assert_eq!(errors[0].message, "Global label \"nowhere\" is not defined");
+
+        let full = assemble_object(&SourceFile::new("full.asm", b".zero 65535\nexit\n".to_vec()), &[]).unwrap();
+        assert_eq!(full.code.len(), 65536);
+        assert_eq!(full.to_bytes().unwrap_err(), "Text size 65536 is larger than 65535");
+        let long = Object { symbols: vec![Symbol { name: "x".repeat(65536), offset: 0, section: SectionKind::Text, global: true }], ..Object::default() };
+        assert_eq!(long.to_bytes().unwrap_err(), "Name length 65536 is larger than 65535");
+    }
+
+    #[test]
+    fn test_image() {
+        let source = b"\
+.data
+msg:
+.string \"ok\"
+.bss
+buffer:
+.zero 4
+.text
+la a, buffer
+la b, msg
+ldb c, b
+stb c, a
+ldb d, a
+";
+        let image = assemble_image(&SourceFile::new("test.asm", source.to_vec()), &[]).unwrap();
+        assert_eq!(image.sections, [
+            Section { kind: SectionKind::Text, address: 0, size: 21, bytes: image.memory()[..21].to_vec() },
+            Section { kind: SectionKind::Data, address: 21, size: 3, bytes: b"ok\0".to_vec() },
+            Section { kind: SectionKind::Bss, address: 24, size: 4, bytes: Vec::new() },
+        ]);
+        assert_eq!(image.memory_size, 28 + STACK_RESERVE);
+        assert_eq!(image.symbols, [("msg".to_string(), 21), ("buffer".to_string(), 24)]);
+
+        let bytes = image.to_bytes().unwrap();
+        assert_eq!(Image::from_bytes(&bytes).unwrap(), image);
+
+        let mut vm = VM::with_config(VMConfig::new().memory_size(image.memory_size)).unwrap();
+        vm.load(&vec![0xFF; image.memory_size]).unwrap();
+        vm.load_image(&image).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.register(4), b'o' as u16);
+
+        let mut small = VM::with_config(VMConfig::new().memory_size(16)).unwrap();
+        assert!(small.load_image(&image).unwrap_err().contains(&format!("needs {} bytes", 28 + STACK_RESERVE)));
+        // Without the reserve the first push would land in the bss
+        let tight = Image { memory_size: 28, ..image.clone() };
+        let mut vm = VM::with_config(VMConfig::new().memory_size(28)).unwrap();
+        assert_eq!(vm.load_image(&tight).unwrap_err(), "Stack at 0x001A would overwrite the Bss section at 0x0018");
+
+        let mut bytes = image.to_bytes().unwrap();
+        bytes[4] = IMAGE_VERSION + 1;
+        assert!(Image::from_bytes(&bytes).unwrap_err().contains("Unsupported image version"));
+        assert!(Image::from_bytes(&image.to_bytes().unwrap()[..20]).is_err());
+
+        let errors = assemble_image(&SourceFile::new("test.asm", b".bss\nnop\n".to_vec()), &[]).unwrap_err();
+        assert_eq!(errors[0].message, "Only labels and .zero are allowed in .bss");
+    }
+
+    #[test]
+    fn test_entry_point() {
+        let vm = run_source(b"helper:\nset a, 1\nret\nmain:\ncall helper\nset b, 2\n");
+        assert_eq!((vm.register(1), vm.register(2)), (1, 2));
+
+        let image = assemble_image(&SourceFile::new("test.asm", b"set a, 1\nstart:\nset b, 2\n.entry start\nmain:\n".to_vec()), &[]).unwrap();
+        assert_eq!(image.entry, 3);
+        let mut vm = VM::new();
+        vm.load_image(&image).unwrap();
+        vm.run().unwrap();
+        assert_eq!((vm.register(1), vm.register(2)), (0, 2));
+        vm.reset();
+        assert_eq!(vm.step().unwrap(), Step::Executed(Inst::SetByte));
+        assert_eq!(vm.register(2), 2);
+
+        let lib = assemble_object(&SourceFile::new("lib.asm", b"nop\n.global main\nmain:\nset a, 3\n".to_vec()), &[]).unwrap();
+        let start = assemble_object(&SourceFile::new("start.asm", b"nop\nboot:\nset a, 4\n.entry boot\n".to_vec()), &[]).unwrap();
+        assert_eq!(link(std::slice::from_ref(&lib)).unwrap().entry, 1);
+        assert_eq!(link(&[lib, start.clone()]).unwrap().entry, 5);
+        assert_eq!(link(&[start.clone(), start]).unwrap_err()[0], "Entry point defined in both start.asm and start.asm");
+
+        let errors = assemble_image(&SourceFile::new("test.asm", b".entry nowhere\n.entry data\n.data\ndata:\n.byte 1\n".to_vec()), &[]).unwrap_err();
+        assert_eq!(errors[0].message, "Duplicate .entry");
+        let errors = assemble_image(&SourceFile::new("test.asm", b".entry data\n.data\ndata:\n.byte 1\n".to_vec()), &[]).unwrap_err();
+        assert_eq!(errors[0].message, "Entry point at 0x0001 is not in the text section");
+    }
+
+    #[test]
+    fn test_listing() {
+        let source = b".equ size, 3\nmain:\n    set a, size ; comment\n.data\nmsg:\n.string \"hello world\"\n.bss\nbuf:\n.zero 4\n";
+        let (image, listing) = assemble_listing(&SourceFile::new("test.asm", source.to_vec()), &[]).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "                                 1  .equ size, 3");
+        assert_eq!(lines[1], "0000                             2  main:");
+        assert_eq!(lines[2], "0000  06 01 03                   3      set a, size ; comment");
+        assert_eq!(lines[5], "0004  68 65 6C 6C 6F 20 77 6F    6  .string \"hello world\"");
+        assert_eq!(lines[6], "000C  72 6C 64 00");
+        assert_eq!(lines[8], "0010                             8  buf:");
+        assert_eq!(lines[9], "0010                             9  .zero 4");
+        assert_eq!(lines[10], "0003  01                            exit ; implicit");
+        assert!(listing.ends_with("Symbols:\n0000  main\n0004  msg\n0010  buf\n      size = 3\n"));
+        assert_eq!(image.entry, 0);
+
+        // Preprocessed lines are listed under the line the user wrote
+        let dir = env::temp_dir().join(format!("vm_listing_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.asm"), "!macro twice(r)\nadd ${r}, ${r}\n!endmacro\n").unwrap();
+        let main = dir.join("main.asm");
+        let source = ".include \"lib.asm\"\nfun double($a) {\n    !twice($a)\n}\ncall double(b)\n";
+        let (_, listing) = assemble_listing(&SourceFile::new(&main.display().to_string(), source.as_bytes().to_vec()), &[]).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "                                 1  .include \"lib.asm\"");
+        assert!(lines[1].starts_with("; ") && lines[1].ends_with("lib.asm"));
+        assert_eq!(lines[2], "                                 1  !macro twice(r)");
+        assert_eq!(lines[6..16], [
+            "                                 2  fun double($a) {",
+            "0000                                + double:",
+            "                                 3      !twice($a)",
+            "0000  0A 01 01                      + add a, a",
+            "                                 4  }",
+            "0003  16                            + ret",
+            "                                 5  call double(b)",
+            "0004  08 02                         + push b",
+            "0006  09 01                         + pop a",
+            "0008  17 00 00                      + call double",
+        ]);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_disassembler() {
+        let source = b"\
+helper:
+    ldb c, b, 2
+    ret
+main:
+    set a, 3
+    la c, -5
+    la d, 7
+loop:
+    sub a, b
+    gt a, z
+    then
+    jmp loop
+    call helper
+    ljmp done
+    dbg a, 255
+done:
+.data
+msg:
+.string \"hi there\"
+.word loop
+.bss
+buf:
+.zero 10
+";
+        let assemble_source = |source: &str| assemble_image(&SourceFile::new("test.asm", source.as_bytes().to_vec()), &[]).unwrap();
+        let image = assemble_source(std::str::from_utf8(source).unwrap());
+        let text = disassemble(&image);
+        assert!(text.starts_with(".entry main\nhelper:\n    ldb c, b, 2             ; 0000\n"));
+        assert!(text.contains("    la c, 0xFFFB            ; 0008\n"));
+        assert!(text.contains("    jmp loop                ; 0017\n    call helper             ; 0019\n    ljmp done"));
+        assert!(text.contains("done:\n.data\nmsg:\n    .byte 0x68, 0x69"));
+        assert!(text.ends_with(".bss\nbuf:\n    .zero 10\n"));
+
+        let reassembled = assemble_source(&text);
+        assert_eq!(reassembled.memory(), image.memory());
+        assert_eq!((reassembled.entry, reassembled.memory_size), (image.entry, image.memory_size));
+        assert_eq!(disassemble(&reassembled), text);
+
+        // Invalid opcodes and registers, a backward jump of 0 and a jump into an instruction stay bytes
+        let program = [Inst::Nop as u8, 200, Inst::JumpBw as u8, 0, Inst::JumpFw as u8, 255, Inst::SetShort as u8, 1, 0, 0, Inst::Push as u8, 16];
+        let text = disassemble(&Image::from_program(&program));
+        assert!(text.contains(".byte 0xC8 "));
+        assert!(text.contains(".byte 0x03, 0x00 "));
+        assert!(text.contains(".byte 0x02, 0xFF "));
+        assert!(text.contains(".byte 0x08 "));
+        assert!(text.starts_with(".noexit\n"));
+        let reassembled = assemble_source(&text);
+        assert_eq!(reassembled.memory(), program);
+        assert_eq!(reassembled.memory_size, Image::from_program(&program).memory_size);
+
+        // Linked objects that end in data
+        let lib = assemble_object(&SourceFile::new("lib.asm", b".global value\n.data\nvalue:\n.word 7\n.bss\n.zero 4\n".to_vec()), &[]).unwrap();
+        let main = assemble_object(&SourceFile::new("main.asm", b"la a, value\nldw b, a\n.bss\n.zero 2\n".to_vec()), &[]).unwrap();
+        assert_eq!((main.text_size, main.data_size(), main.bss_size), (8, 0, 2));
+        let image = link(&[main, lib]).unwrap();
+        let sections: Vec<_> = image.sections.iter().map(|section| (section.kind, section.address, section.size)).collect();
+        assert_eq!(sections, [(SectionKind::Text, 0, 9), (SectionKind::Data, 9, 2), (SectionKind::Bss, 11, 6)]);
+        assert_eq!(image.memory()[8..11], [Inst::Exit as u8, 0, 7]);
+        let text = disassemble(&image);
+        let reassembled = assemble_source(&text);
+        assert_eq!(reassembled.memory(), image.memory());
+        assert_eq!(reassembled.memory_size, image.memory_size);
+        assert_eq!(assemble_source("exit\n.noexit\n").memory(), [Inst::Exit as u8]);
+
+        let instruction = decode(&[Inst::JumpBw as u8, 4], 0x10).unwrap();
+        assert_eq!(instruction.operands, [Operand::Target(0x0E)]);
+        assert_eq!(instruction.to_string(), "jmp 0x000E");
+        assert_eq!(decode(&[Inst::StoreWord as u8, 1, 15, 4], 0).unwrap().to_string(), "stw a, sp, 4");
+        assert_eq!(decode(&[Inst::SetShort as u8, 1, 0], 0), None);
+    }
+
+    #[test]
+    fn test_debugger() {
+        let source = b"\
+helper:
+    add a, b
+    ret
+main:
+    set a, 3
+    set b, 2
+    call helper
+    call helper
+    dbg a, 0
+";
+        let (image, lines) = assemble_debug(&SourceFile::new("test.asm", source.to_vec()), &[]).unwrap();
+        assert_eq!(lines[2], SourceLine { address: 4, file: "test.asm".to_string(), line: 5, text: "set a, 3".to_string() });
+
+        let mut vm = VM::new();
+        vm.load_image(&image).unwrap();
+        let mut debugger = Debugger::new(vm, image.symbols.clone(), lines);
+
+        assert_eq!(debugger.command("break helper").unwrap(), "Breakpoint set at 0x0000 <helper> test.asm:2  add a, b");
+        assert_eq!(debugger.command("step").unwrap(), "0x0007 <main+3> test.asm:6  set b, 2");
+        assert_eq!(debugger.command("c").unwrap(), "Breakpoint at 0x0000 <helper> test.asm:2  add a, b");
+        assert_eq!(debugger.command("finish").unwrap(), "0x000D <main+9> test.asm:8  call helper");
+        assert_eq!(debugger.vm().register(1), 5);
+
+        debugger.command("set b 10").unwrap();
+        debugger.command("break test.asm:9").unwrap();
+        assert_eq!(debugger.command("next").unwrap(), "Breakpoint at 0x0000 <helper> test.asm:2  add a, b");
+        assert_eq!(debugger.command("c").unwrap(), "Breakpoint at 0x0010 <main+12> test.asm:9  dbg a, 0");
+        assert_eq!(debugger.vm().register(1), 15);
+        assert!(debugger.command("regs").unwrap().contains("\na   0x000F     15\n"));
+        assert_eq!(debugger.command("mem 4 3").unwrap(), "0004  06 01 03");
+        assert!(debugger.command("disasm main").unwrap().starts_with("main:\n    0004  set a, 3\n"));
+
+        assert_eq!(debugger.command("set pc main").unwrap_err(), "Expected a number, found \"main\"");
+        assert_eq!(debugger.command("break nowhere").unwrap_err(), "Unknown location \"nowhere\"");
+
+        // pc past the end of memory faults instead of panicking
+        assert_eq!(debugger.command("set pc 0xFFFF").unwrap(), "0xFFFF <main+65531>");
+        assert_eq!(debugger.command("next").unwrap(), "Memory access out of bounds at 0xFFFF at pc 0xFFFF\n0xFFFF <main+65531>");
+        assert_eq!(debugger.command("set pc 0x10").unwrap(), "0x0010 <main+12> test.asm:9  dbg a, 0");
+        assert_eq!(debugger.command("c").unwrap(), "Program exited");
+
+        // Commands come from any reader, an empty line repeats the last one
+        let mut vm = VM::new();
+        vm.load_image(&image).unwrap();
+        let mut debugger = Debugger::new(vm, image.symbols.clone(), Vec::new());
+        let mut output = Vec::new();
+        debugger.repl(&b"step\n\nquit\nstep\n"[..], &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "\
+0x0004 <main>  set a, 3
+(vm) 0x0007 <main+3>  set b, 2
+(vm) 0x000A <main+6>  call helper
+(vm) ");
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let source = b"\
+main:
+    set a, 5
+    push a
+    la b, value
+    ldw c, b
+    stb a, b, 1
+    pop d
+.data
+value:
+.word 0x1234
+";
+        let image = assemble_image(&SourceFile::new("test.asm", source.to_vec()), &[]).unwrap();
+        let value = image.symbols.iter().find(|(name, _)| name == "value").unwrap().1 as usize;
+        let mut vm = VM::new();
+        vm.load_image(&image).unwrap();
+
+        let stack = vm.register(15) as usize;
+        let write = vm.add_watchpoint(stack, 2, WatchKind::Write).unwrap();
+        let read = vm.add_watchpoint(value, 1, WatchKind::Read).unwrap();
+        assert_eq!(vm.add_watchpoint(value, 0, WatchKind::Read).unwrap_err(), "Watchpoint of 0 bytes");
+        assert!(vm.add_watchpoint(vm.memory_size() - 1, 2, WatchKind::Write).is_err());
+        let hit = match vm.run() {
+            Ok(ExitReason::Watchpoint(hit)) => hit,
+            other => panic!("Expected a watchpoint, got {:?}", other),
+        };
+        assert_eq!(hit, WatchHit {
+            id: write,
+            pc: 3,
+            inst: Inst::Push,
+            access: MemoryAccess { kind: AccessKind::Write, address: stack, width: 2, old: 0, new: 5 },
+        });
+        assert_eq!(hit.to_string(), format!("Watchpoint 1: 0x0000 -> 0x0005 at 0x{:04X} by push at pc 0x0003", stack));
+
+        // A word read overlapping the first byte
+        let hit = match vm.run() {
+            Ok(ExitReason::Watchpoint(hit)) => hit,
+            other => panic!("Expected a watchpoint, got {:?}", other),
+        };
+        assert_eq!((hit.id, hit.inst, hit.access.old), (read, Inst::LoadWord, 0x1234));
+        assert_eq!(vm.register(3), 0x1234);
+
+        // Byte stores next to a watched range don't count
+        assert!(vm.remove_watchpoint(read));
+        vm.add_watchpoint(value, 1, WatchKind::ReadWrite).unwrap();
+        assert_eq!(vm.step(), Ok(Step::Executed(Inst::StoreByte)));
+        assert_eq!(vm.step(), Ok(Step::Executed(Inst::Pop)));
+        assert_eq!(vm.register(4), 5);
+        assert_eq!(vm.run(), Ok(ExitReason::Exit));
+        assert!(!vm.remove_watchpoint(read));
+
+        let mut vm = VM::new();
+        vm.load_image(&image).unwrap();
+        let mut debugger = Debugger::new(vm, image.symbols, Vec::new());
+        assert_eq!(debugger.command("awatch value 1").unwrap(), format!("Watchpoint 1 on 0x{:04X}..0x{:04X}", value, value + 1));
+        assert_eq!(debugger.command("c").unwrap(), "Watchpoint 1: read 0x1234 at 0x0014 by ldw at pc 0x0009\n0x000D <main+13>  stb a, b, 1");
+        debugger.command("unwatch 1").unwrap();
+        assert_eq!(debugger.command("unwatch 1").unwrap_err(), "No watchpoint 1");
+        assert_eq!(debugger.command("watch 0xFFFF 65535").unwrap_err(), "Watchpoint of 65535 bytes at 0xFFFF doesn't fit in 1024 bytes of memory");
+        assert_eq!(debugger.command("c").unwrap(), "Program exited");
+    }
+
+    #[test]
+    fn test_trace() {
+        let source = b"\
+main:
+    set a, 3
+loop:
+    push a
+    stb a, sp
+    set b, 1
+    sub a, b
+    gt a, z
+    then
+    jmp loop
+    call done
+done:
+";
+        let image = assemble_image(&SourceFile::new("test.asm", source.to_vec()), &[]).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&image).unwrap();
+
+        // The state after every step, to compare with the replay
+        let mut states = vec![vm.state()];
+        vm.start_trace();
+        while vm.step().unwrap() != Step::Exited {
+            states.push(vm.state());
+        }
+        states.push(vm.state());
+        let trace = vm.stop_trace().unwrap();
+        assert!(vm.trace().is_none());
+
+        assert_eq!(trace.entries.len(), states.len() - 1);
+        for (step, state) in states.iter().enumerate() {
+            assert_eq!(&trace.state_at(step).unwrap(), state);
+        }
+        assert_eq!(trace.state_at(states.len()).unwrap_err(), format!("Step {} is past the end of the trace, which has {} steps", states.len(), states.len() - 1));
+
+        let bytes = trace.to_bytes();
+        assert!(Trace::is_trace(&bytes));
+        assert_eq!(Trace::from_bytes(&bytes).unwrap(), trace);
+        assert_eq!(Trace::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err(), "Unexpected end of file");
+
+        let json = trace.to_json_lines();
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!(lines.len(), trace.entries.len());
+        assert_eq!(lines[1], r#"{"step":1,"pc":3,"inst":"Push","text":"push a","operands":[1],"registers":{"sp":1020},"memory":[[1022,0],[1023,3]],"skip_flag":false,"next_pc":5}"#);
+        assert_eq!(lines[2], r#"{"step":2,"pc":5,"inst":"StoreByte","text":"stb a, sp, 0","operands":[1,15,0],"registers":{},"memory":[[1020,3]],"skip_flag":false,"next_pc":9}"#);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let source = b"\
+main:
+    set a, 3
+    la b, value
+loop:
+    push a
+    stw a, b
+    set c, 1
+    sub a, c
+    gt a, z
+    then
+    jmp loop
+    call done
+done:
+.data
+value:
+.word 0x1234
+";
+        let image = assemble_image(&SourceFile::new("test.asm", source.to_vec()), &[]).unwrap();
+        let mut vm = VM::with_config(VMConfig::new().history_size(1000)).unwrap();
+        vm.load_image(&image).unwrap();
+
+        let mut states = vec![vm.state()];
+        while vm.step().unwrap() != Step::Exited {
+            states.push(vm.state());
+        }
+        assert_eq!(vm.history_len(), states.len());
+
+        // Undoing the exit runs it again
+        assert_eq!(vm.step_back(), Some(Step::Executed(Inst::Exit)));
+        assert_eq!(vm.step(), Ok(Step::Exited));
+        assert_eq!(vm.step_back(), Some(Step::Executed(Inst::Exit)));
+        for state in states.iter().rev() {
+            assert_eq!(&vm.state(), state);
+            vm.step_back();
+        }
+        assert_eq!(vm.step_back(), None);
+        assert_eq!(vm.run(), Ok(ExitReason::Exit));
+
+        // The last store to `value` was of 1
+        let value = image.symbols.iter().find(|(name, _)| name == "value").unwrap().1 as usize;
+        vm.add_watchpoint(value, 2, WatchKind::Write).unwrap();
+        let hit = vm.reverse_continue().unwrap();
+        assert_eq!((hit.inst, hit.access.old, hit.access.new), (Inst::StoreWord, 2, 1));
+        assert_eq!((vm.pc(), vm.register(1)), (hit.pc, 1));
+        assert_eq!(vm.reverse_continue().unwrap().access.new, 2);
+
+        // Only the last instructions are kept
+        let mut vm = VM::with_config(VMConfig::new().history_size(2)).unwrap();
+        vm.load_image(&image).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.history_len(), 2);
+        assert!(vm.step_back().is_some() && vm.step_back().is_some() && vm.step_back().is_none());
+        assert_eq!(vm.pc(), states[states.len() - 2].pc);
+
+        let mut vm = VM::with_config(VMConfig::new().history_size(1000)).unwrap();
+        vm.load_image(&image).unwrap();
+        let mut debugger = Debugger::new(vm, image.symbols, Vec::new());
+        assert_eq!(debugger.command("back").unwrap_err(), "No history to step back into");
+        debugger.command("break loop").unwrap();
+        assert_eq!(debugger.command("c").unwrap(), "Breakpoint at 0x0007 <loop>  push a");
+        debugger.command("c").unwrap();
+        debugger.command("watch value").unwrap();
+        assert_eq!(debugger.command("rc").unwrap(), "Watchpoint 1: 0x1234 -> 0x0003 at 0x001D by stw at pc 0x0009\n0x0009 <loop+2>  stw a, b, 0");
+        assert_eq!(debugger.command("back").unwrap(), "0x0007 <loop>  push a");
+        debugger.command("unwatch 1").unwrap();
+        assert_eq!(debugger.command("rc").unwrap(), "Reached the start of the history\n0x0000 <main>  set a, 3");
+    }
+}
@@ -0,0 +1,195 @@
+use std::convert::TryFrom;
+
+use crate::image::SectionKind;
+
+// Relocatable output of the assembler, combined into a program by the linker.
+//
+// All integers are big-endian, names are a u16 length followed by UTF-8 bytes.
+//
+// "VMOB" version:u8
+// text_size:u16 data_size:u16 bss_size:u16 code        code is the text then the data
+// symbol_count:u16 (global:u8 section:u8 offset:u16 name)*
+// import_count:u16 name*
+// relocation_count:u16 (offset:u16 addend:i32 name)*
+// entry:name                                          empty without `.entry`
+pub const OBJECT_MAGIC: &[u8; 4] = b"VMOB";
+pub const OBJECT_VERSION: u8 = 3;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Object {
+    // Source or object file name, for linker errors. Not stored in the file.
+    pub name: String,
+    // Text then data, laid out from offset 0 with the bss after them
+    pub code: Vec<u8>,
+    pub text_size: usize,
+    pub bss_size: usize,
+    // Every label, only global ones are visible to other objects
+    pub symbols: Vec<Symbol>,
+    // Symbols used but not defined here
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    // Label given to `.entry`
+    pub entry: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    // From the start of the object, not of the section
+    pub offset: u16,
+    pub section: SectionKind,
+    pub global: bool,
+}
+
+// Big-endian word at `offset` to be set to the address of `symbol` plus `addend`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Relocation {
+    pub offset: u16,
+    pub symbol: String,
+    pub addend: i32,
+}
+
+impl Object {
+    pub fn data_size(&self) -> usize {
+        self.code.len() - self.text_size
+    }
+
+    // Offset in the object where the section starts
+    pub fn section_offset(&self, kind: SectionKind) -> usize {
+        match kind {
+            SectionKind::Text => 0,
+            SectionKind::Data => self.text_size,
+            SectionKind::Bss => self.code.len(),
+        }
+    }
+
+    // Fails when a length or count doesn't fit in its u16
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = OBJECT_MAGIC.to_vec();
+        bytes.push(OBJECT_VERSION);
+
+        write_len(&mut bytes, self.text_size, "Text size")?;
+        write_len(&mut bytes, self.data_size(), "Data size")?;
+        write_len(&mut bytes, self.bss_size, "Bss size")?;
+        bytes.extend_from_slice(&self.code);
+
+        write_len(&mut bytes, self.symbols.len(), "Symbol count")?;
+        for symbol in &self.symbols {
+            bytes.push(symbol.global as u8);
+            bytes.push(symbol.section as u8);
+            write_u16(&mut bytes, symbol.offset as usize);
+            write_name(&mut bytes, &symbol.name)?;
+        }
+
+        write_len(&mut bytes, self.imports.len(), "Import count")?;
+        for import in &self.imports {
+            write_name(&mut bytes, import)?;
+        }
+
+        write_len(&mut bytes, self.relocations.len(), "Relocation count")?;
+        for relocation in &self.relocations {
+            write_u16(&mut bytes, relocation.offset as usize);
+            bytes.extend_from_slice(&relocation.addend.to_be_bytes());
+            write_name(&mut bytes, &relocation.symbol)?;
+        }
+
+        write_name(&mut bytes, self.entry.as_deref().unwrap_or(""))?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Object, String> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        if reader.take(4)? != OBJECT_MAGIC {
+            return Err(format!("{} is not an object file", name));
+        }
+        let version = reader.u8()?;
+        if version != OBJECT_VERSION {
+            return Err(format!("{} has unsupported object version {}", name, version));
+        }
+
+        let text_size = reader.u16()? as usize;
+        let data_size = reader.u16()? as usize;
+        let bss_size = reader.u16()? as usize;
+        let code = reader.take(text_size + data_size)?.to_vec();
+
+        let mut symbols = Vec::new();
+        for _ in 0..reader.u16()? {
+            let global = reader.u8()? != 0;
+            let kind = reader.u8()?;
+            let section = SectionKind::from_byte(kind).ok_or_else(|| format!("{} has unknown section kind {}", name, kind))?;
+            let offset = reader.u16()?;
+            symbols.push(Symbol { name: reader.name()?, offset, section, global });
+        }
+
+        let mut imports = Vec::new();
+        for _ in 0..reader.u16()? {
+            imports.push(reader.name()?);
+        }
+
+        let mut relocations = Vec::new();
+        for _ in 0..reader.u16()? {
+            let offset = reader.u16()?;
+            let addend = reader.u32()? as i32;
+            relocations.push(Relocation { offset, symbol: reader.name()?, addend });
+        }
+
+        let entry = Some(reader.name()?).filter(|entry| !entry.is_empty());
+
+        if reader.pos != bytes.len() {
+            return Err(format!("{} has trailing bytes", name));
+        }
+        Ok(Object { name: name.to_string(), code, text_size, bss_size, symbols, imports, relocations, entry })
+    }
+}
+
+pub(crate) fn write_u16(bytes: &mut Vec<u8>, value: usize) {
+    bytes.extend_from_slice(&(value as u16).to_be_bytes());
+}
+
+pub(crate) fn write_len(bytes: &mut Vec<u8>, len: usize, what: &str) -> Result<(), String> {
+    if len > u16::MAX as usize {
+        return Err(format!("{} {} is larger than {}", what, len, u16::MAX));
+    }
+    write_u16(bytes, len);
+    Ok(())
+}
+
+pub(crate) fn write_name(bytes: &mut Vec<u8>, name: &str) -> Result<(), String> {
+    write_len(bytes, name.len(), "Name length")?;
+    bytes.extend_from_slice(name.as_bytes());
+    Ok(())
+}
+
+pub(crate) struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.pos + len > self.bytes.len() {
+            return Err("Unexpected end of file".to_string());
+        }
+        self.pos += len;
+        Ok(&self.bytes[self.pos - len..self.pos])
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(<[u8; 4]>::try_from(self.take(4)?).unwrap()))
+    }
+
+    pub fn name(&mut self) -> Result<String, String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "Invalid symbol name".to_string())
+    }
+}
@@ -0,0 +1,722 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostic::{Diagnostic, Origin, Source, SourceFile, Span};
+
+// Guards against macros that (directly or indirectly) invoke themselves
+pub const MAX_MACRO_DEPTH: usize = 32;
+// Guards against macros that invoke others several times, which grows exponentially with depth
+pub const MAX_MACRO_EXPANSIONS: usize = 100_000;
+
+// Registers that can be function parameters or be saved by a function
+const GENERAL_REGISTERS: [&str; 13] = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m"];
+
+// Produces a new source whose lines remember where they came from. Includes are expanded
+// first so macros can be shared between files, and macros before functions so they can
+// generate them.
+pub fn preprocess(file: &SourceFile, include_paths: &[PathBuf]) -> Result<SourceFile, Vec<Diagnostic>> {
+    let included = expand_includes(file, include_paths)?;
+    let expanded = expand_macros(&included)?;
+    expand_functions(&expanded)
+}
+
+// Replaces `.include "path"` lines with the contents of the file.
+//
+// Paths are relative to the including file, then to each include path in order. Every file is
+// included at most once, later includes of the same file are ignored.
+fn expand_includes(file: &SourceFile, include_paths: &[PathBuf]) -> Result<SourceFile, Vec<Diagnostic>> {
+    let mut includer = Includer {
+        include_paths,
+        included: HashSet::new(),
+        stack: Vec::new(),
+        sources: Vec::new(),
+        output: Output::default(),
+        errors: Vec::new(),
+    };
+
+    // The root file may not exist on disk, e.g. in tests
+    let root = fs::canonicalize(&file.name).ok();
+    if let Some(path) = &root {
+        includer.included.insert(path.clone());
+    }
+    includer.include(file, root);
+
+    if includer.errors.is_empty() {
+        let mut source = includer.output.into_source(file);
+        source.sources = includer.sources;
+        Ok(source)
+    } else {
+        Err(includer.errors)
+    }
+}
+
+// Expands `!macro` definitions and `!name(args)` invocations.
+//
+// !macro name(param, ...)
+// ... ${param} ... ${macro_id} ...
+// !endmacro
+//
+// `${macro_id}` is unique for every expansion, so it can be used to build local labels.
+// Arguments are separated by commas, wrap an argument in parentheses to use commas inside it.
+fn expand_macros(file: &SourceFile) -> Result<SourceFile, Vec<Diagnostic>> {
+    let mut preprocessor = Preprocessor {
+        file,
+        macros: HashMap::new(),
+        expansions: 0,
+        output: Output::default(),
+        errors: Vec::new(),
+    };
+
+    preprocessor.run();
+
+    if preprocessor.errors.is_empty() {
+        Ok(preprocessor.output.into_source(file))
+    } else {
+        Err(preprocessor.errors)
+    }
+}
+
+// Expands function declarations and the calls to them.
+//
+// fun name($reg, ...) use ($reg, ...) {
+// ... $reg ... ${fun}_label ...
+// }
+//
+// Parameters and `use`d registers are written with `$` inside the body, `use`d registers are
+// saved on entry and restored by an epilogue at the end, which every `ret` jumps to so it stays a
+// single instruction after `then` or `else`. `call name(reg, ...) use (reg, ...)` moves the
+// arguments into the parameter registers and is checked against the declaration, calls that need
+// to move them can't follow `then` or `else`. A bare `call name` is only allowed without parameters.
+fn expand_functions(file: &SourceFile) -> Result<SourceFile, Vec<Diagnostic>> {
+    let lines = split_lines(&file.text);
+    let mut functions = HashMap::new();
+    let mut errors = Vec::new();
+
+    for (line, span) in &lines {
+        if let Some(header) = function_header(strip_comment(line).trim()) {
+            match parse_signature(header, true) {
+                Ok((name, function)) => {
+                    if functions.insert(name.to_string(), function).is_some() {
+                        errors.push(file.error(*span, format!("Duplicate function {:?}", name)));
+                    }
+                }
+                Err(message) => errors.push(file.error(*span, message)),
+            }
+        }
+    }
+
+    let mut output = Output::default();
+    // Function being expanded, with the span of its header
+    let mut current: Option<(&str, &Function, Span)> = None;
+    // Last line with code, to find `ret` at the end of a function and calls after `then` and `else`
+    let mut last_code = String::new();
+
+    for (index, (line, span)) in lines.iter().enumerate() {
+        let origin = file.origin(index);
+        let code = strip_comment(line).trim();
+
+        if let Some(header) = function_header(code) {
+            if current.is_some() {
+                errors.push(file.error(*span, "Functions can't be nested".to_string()));
+                continue;
+            }
+            if let Ok((name, _)) = parse_signature(header, true) {
+                let (name, function) = functions.get_key_value(name).unwrap();
+
+                output.emit(&format!("{}:", name), origin.clone());
+                for reg in &function.uses {
+                    output.emit(&format!("push {}", reg), origin.clone());
+                }
+                current = Some((name, function, *span));
+                last_code.clear();
+            }
+            continue;
+        }
+
+        if code == "}" {
+            match current.take() {
+                Some((name, function, _)) => {
+                    if !function.uses.is_empty() || last_code != "ret" {
+                        function.emit_epilogue(name, &mut output, &origin);
+                    }
+                }
+                None => errors.push(file.error(*span, "Unexpected } outside of a function".to_string())),
+            }
+            continue;
+        }
+
+        let line = match current {
+            Some((name, function, _)) => match substitute_registers(line, name, function) {
+                Ok(line) => line,
+                Err(message) => {
+                    errors.push(file.error(*span, message));
+                    continue;
+                }
+            },
+            None if code.contains("${fun}") => {
+                errors.push(file.error(*span, "${fun} outside of a function".to_string()));
+                continue;
+            }
+            None => line.to_string(),
+        };
+        let code = strip_comment(&line).trim();
+
+        if let Some(call) = code.strip_prefix("call ").filter(|call| call.contains('(')) {
+            match expand_call(call, &functions) {
+                Ok(lines) if lines.len() > 1 && matches!(last_code.as_str(), "then" | "else") => {
+                    let message = format!("Call that moves arguments can't follow {}, it isn't a single instruction", last_code);
+                    errors.push(file.error(*span, message));
+                }
+                Ok(lines) => lines.iter().for_each(|line| output.emit(line, origin.clone())),
+                Err(message) => errors.push(file.error(*span, message)),
+            }
+        } else if let Some((name, function)) = code.strip_prefix("call ").and_then(|name| functions.get_key_value(name.trim())) {
+            if function.params.is_empty() {
+                output.emit(&line, origin);
+            } else {
+                let message = format!("Function {:?} expects {} arguments but the call has no argument list", name, function.params.len());
+                errors.push(file.error(*span, message));
+            }
+        } else if let (Some((name, function, _)), "ret") = (current, code) {
+            function.emit_return(name, &mut output, &origin);
+        } else {
+            output.emit(&line, origin);
+        }
+
+        if !code.is_empty() {
+            last_code = code.to_string();
+        }
+    }
+
+    if let Some((name, _, span)) = current {
+        errors.push(file.error(span, format!("Missing }} at the end of function {:?}", name)));
+    }
+
+    if errors.is_empty() {
+        Ok(output.into_source(file))
+    } else {
+        Err(errors)
+    }
+}
+
+struct Includer<'a> {
+    include_paths: &'a [PathBuf],
+    // Canonical paths of every file read so far
+    included: HashSet<PathBuf>,
+    // Files currently being included, to report cycles
+    stack: Vec<PathBuf>,
+    // Every file read, in the order the includes were reached
+    sources: Vec<Source>,
+    output: Output,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'a> Includer<'a> {
+    fn include(&mut self, file: &SourceFile, path: Option<PathBuf>) {
+        self.stack.extend(path.clone());
+        let source = self.sources.len();
+        self.sources.push(Source { name: file.name.clone(), text: file.text.clone(), includes: Vec::new() });
+
+        for (index, (line, span)) in split_lines(&file.text).into_iter().enumerate() {
+            let code = strip_comment(line).trim();
+
+            match code.strip_prefix(".include") {
+                Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+                    match self.include_file(file, rest.trim()) {
+                        Ok(Some(included)) => self.sources[source].includes.push((index, included)),
+                        Ok(None) => {}
+                        Err(message) => self.errors.push(file.error(span, message)),
+                    }
+                }
+                _ => self.output.emit(line, file.origin(index)),
+            }
+        }
+
+        if path.is_some() {
+            self.stack.pop();
+        }
+    }
+
+    // Returns the index of the file in `sources` when it wasn't included before
+    fn include_file(&mut self, file: &SourceFile, arg: &str) -> Result<Option<usize>, String> {
+        let name = arg.strip_prefix('"').and_then(|arg| arg.strip_suffix('"'))
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| format!("Expected a quoted path after .include, found {:?}", arg))?;
+
+        let dir = Path::new(&file.name).parent().unwrap_or_else(|| Path::new(""));
+        let found = std::iter::once(dir.to_path_buf())
+            .chain(self.include_paths.iter().cloned())
+            .map(|dir| dir.join(name))
+            .find(|candidate| candidate.is_file())
+            .ok_or_else(|| format!("Unable to find included file {:?}", name))?;
+        let canonical = fs::canonicalize(&found)
+            .map_err(|err| format!("Unable to read {}: {}", found.display(), err))?;
+
+        if let Some(start) = self.stack.iter().position(|path| *path == canonical) {
+            let cycle: Vec<String> = self.stack[start..].iter().chain(Some(&canonical))
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(format!("Include cycle: {}", cycle.join(" -> ")));
+        }
+        if !self.included.insert(canonical.clone()) {
+            return Ok(None);
+        }
+
+        let text = fs::read(&found).map_err(|err| format!("Unable to read {}: {}", found.display(), err))?;
+        let included = self.sources.len();
+        self.include(&SourceFile::new(&found.display().to_string(), text), Some(canonical));
+        Ok(Some(included))
+    }
+}
+
+#[derive(Default)]
+struct Output {
+    text: Vec<u8>,
+    origins: Vec<Origin>,
+}
+
+impl Output {
+    fn emit(&mut self, line: &str, origin: Origin) {
+        self.text.extend_from_slice(line.as_bytes());
+        self.text.push(b'\n');
+        self.origins.push(origin);
+    }
+
+    fn into_source(self, file: &SourceFile) -> SourceFile {
+        let mut source = SourceFile::new(&file.name, self.text);
+        source.origins = self.origins;
+        source.sources = file.sources.clone();
+        source
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+struct Preprocessor<'a> {
+    file: &'a SourceFile,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    output: Output,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'a> Preprocessor<'a> {
+    fn run(&mut self) {
+        let lines = split_lines(&self.file.text);
+        let mut index = 0;
+
+        while index < lines.len() {
+            let (line, span) = lines[index];
+            let code = strip_comment(line).trim();
+
+            // `!macrox(a)` invokes a macro named `macrox`
+            let header = code.strip_prefix("!macro").filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+            if let Some(header) = header {
+                let end = lines[index + 1..].iter()
+                    .position(|(line, _)| strip_comment(line).trim() == "!endmacro")
+                    .map(|i| index + 1 + i);
+
+                match end {
+                    Some(end) => {
+                        let body = lines[index + 1..end].iter().map(|(line, _)| line.to_string()).collect();
+                        self.define(header, body, span);
+                        index = end + 1;
+                    }
+                    None => {
+                        self.errors.push(self.file.error(span, "Missing !endmacro".to_string()));
+                        return;
+                    }
+                }
+                continue;
+            }
+
+            let origin = self.file.origin(index);
+
+            if code == "!endmacro" {
+                self.errors.push(self.file.error(span, "!endmacro without !macro".to_string()));
+            } else if code.starts_with('!') {
+                self.expand(code, span, &origin, 0);
+            } else if substitutions(code).iter().any(|name| *name != "fun") {
+                self.errors.push(self.file.error(span, "Macro substitution outside of a macro".to_string()));
+            } else {
+                self.output.emit(line, origin);
+            }
+            index += 1;
+        }
+    }
+
+    fn define(&mut self, header: &str, body: Vec<String>, span: Span) {
+        let (name, params) = match parse_call(header.trim().trim_end_matches(':')) {
+            Some(call) => call,
+            None => {
+                self.errors.push(self.file.error(span, "Invalid macro declaration".to_string()));
+                return;
+            }
+        };
+
+        if name == "macro" || name == "endmacro" {
+            self.errors.push(self.file.error(span, format!("Invalid macro name {:?}", name)));
+            return;
+        }
+        if params.iter().any(|param| !is_identifier(param)) {
+            self.errors.push(self.file.error(span, "Macro parameters must be identifiers".to_string()));
+            return;
+        }
+
+        for line in &body {
+            for name in substitutions(line) {
+                if name != "macro_id" && name != "fun" && !params.iter().any(|param| param == name) {
+                    self.errors.push(self.file.error(span, format!("Unknown macro parameter {:?}", name)));
+                }
+            }
+        }
+
+        if self.macros.insert(name.to_string(), Macro { params, body }).is_some() {
+            self.errors.push(self.file.error(span, format!("Duplicate macro {:?}", name)));
+        }
+    }
+
+    // `span` and `origin` always refer to the invocation written by the user. Returns false when
+    // the expansion ran away, the rest of the invocation is then dropped so it's reported once.
+    fn expand(&mut self, code: &str, span: Span, origin: &Origin, depth: usize) -> bool {
+        if depth >= MAX_MACRO_DEPTH {
+            self.errors.push(self.file.error(span, format!("Macro expansion deeper than {} levels", MAX_MACRO_DEPTH)));
+            return false;
+        }
+        if self.expansions >= MAX_MACRO_EXPANSIONS {
+            self.errors.push(self.file.error(span, format!("More than {} macro expansions", MAX_MACRO_EXPANSIONS)));
+            return false;
+        }
+
+        let (name, args) = match parse_call(&code[1..]) {
+            Some(call) => call,
+            None => {
+                self.errors.push(self.file.error(span, "Invalid macro invocation".to_string()));
+                return true;
+            }
+        };
+
+        let lines = match self.macros.get(name) {
+            Some(mac) if mac.params.len() != args.len() => {
+                let message = format!("Macro {:?} expects {} arguments but {} were given", name, mac.params.len(), args.len());
+                self.errors.push(self.file.error(span, message));
+                return true;
+            }
+            Some(mac) => {
+                self.expansions += 1;
+                let id = format!("__{}_{}", name, self.expansions);
+
+                mac.body.iter()
+                    .map(|line| substitute(line, &mac.params, &args, &id))
+                    .collect::<Vec<_>>()
+            }
+            None => {
+                self.errors.push(self.file.error(span, format!("Unknown macro {:?}", name)));
+                return true;
+            }
+        };
+
+        let origin = Origin {
+            expansion: Some(origin.expansion.clone().unwrap_or_else(|| name.to_string())),
+            ..origin.clone()
+        };
+
+        for line in lines {
+            let code = strip_comment(&line).trim();
+
+            if !code.starts_with('!') {
+                self.output.emit(&line, origin.clone());
+            } else if !self.expand(code, span, &origin, depth + 1) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+struct Function {
+    params: Vec<String>,
+    uses: Vec<String>,
+}
+
+impl Function {
+    fn emit_return(&self, name: &str, output: &mut Output, origin: &Origin) {
+        if self.uses.is_empty() {
+            output.emit("ret", origin.clone());
+        } else {
+            output.emit(&format!("jmp __{}_ret", name), origin.clone());
+        }
+    }
+
+    fn emit_epilogue(&self, name: &str, output: &mut Output, origin: &Origin) {
+        if !self.uses.is_empty() {
+            output.emit(&format!("__{}_ret:", name), origin.clone());
+        }
+        for reg in self.uses.iter().rev() {
+            output.emit(&format!("pop {}", reg), origin.clone());
+        }
+        output.emit("ret", origin.clone());
+    }
+}
+
+fn function_header(code: &str) -> Option<&str> {
+    code.strip_prefix("fun ")?.strip_suffix('{')
+}
+
+// Parses `name(reg, ...) use (reg, ...)`, declarations write the registers with `$`
+fn parse_signature(text: &str, declaration: bool) -> Result<(&str, Function), String> {
+    let invalid = || format!("Invalid function signature {:?}", text.trim());
+
+    let params_end = text.find(')').ok_or_else(invalid)? + 1;
+    let (name, params) = parse_call(&text[..params_end]).ok_or_else(invalid)?;
+
+    let uses = match text[params_end..].trim() {
+        "" => Vec::new(),
+        rest if rest.starts_with("use") => match parse_call(rest) {
+            Some(("use", uses)) => uses,
+            _ => return Err(invalid()),
+        },
+        _ => return Err(invalid()),
+    };
+
+    let register = |arg: &String| {
+        let reg = if declaration { arg.strip_prefix('$') } else { Some(arg.as_str()) };
+
+        match reg {
+            Some(reg) if GENERAL_REGISTERS.contains(&reg) => Ok(reg.to_string()),
+            _ => Err(format!("Expected a register {}, found {:?}", if declaration { "like $a" } else { "name" }, arg)),
+        }
+    };
+
+    let params = params.iter().map(register).collect::<Result<Vec<_>, _>>()?;
+    let uses = uses.iter().map(register).collect::<Result<Vec<_>, _>>()?;
+
+    Ok((name, Function { params, uses }))
+}
+
+// `$reg` -> `reg` and `${fun}` -> function name
+fn substitute_registers(line: &str, name: &str, function: &Function) -> Result<String, String> {
+    let line = line.replace("${fun}", name);
+    let mut result = String::new();
+    let mut rest = line.as_str();
+
+    while let Some(start) = rest.find('$') {
+        result += &rest[..start];
+        rest = &rest[start + 1..];
+
+        let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+        let reg = &rest[..end];
+
+        // `$0`..`$12` are register aliases, not function registers
+        if reg.starts_with(|c: char| c.is_ascii_digit()) {
+            result.push('$');
+        } else if !function.params.iter().chain(&function.uses).any(|r| r == reg) {
+            return Err(format!("Register ${} is not a parameter or in the use list of {:?}", reg, name));
+        }
+        result += reg;
+        rest = &rest[end..];
+    }
+    Ok(result + rest)
+}
+
+fn expand_call(call: &str, functions: &HashMap<String, Function>) -> Result<Vec<String>, String> {
+    let (name, args) = parse_signature(call, false)?;
+    let function = functions.get(name).ok_or_else(|| format!("Call to unknown function {:?}", name))?;
+    let mut lines = Vec::new();
+
+    if args.params.len() != function.params.len() {
+        return Err(format!(
+            "Function {:?} expects {} arguments but {} were given",
+            name, function.params.len(), args.params.len()
+        ));
+    }
+
+    if !args.uses.is_empty() && sorted(&args.uses) != sorted(&function.uses) {
+        return Err(format!(
+            "Function {:?} uses ({}) but the call lists ({})",
+            name, function.uses.join(", "), args.uses.join(", ")
+        ));
+    }
+
+    // Go through the stack so arguments can be any permutation of the parameters
+    if args.params != function.params {
+        for reg in &args.params {
+            lines.push(format!("push {}", reg));
+        }
+        for reg in function.params.iter().rev() {
+            lines.push(format!("pop {}", reg));
+        }
+    }
+
+    lines.push(format!("call {}", name));
+    Ok(lines)
+}
+
+fn sorted(regs: &[String]) -> Vec<&String> {
+    let mut regs: Vec<&String> = regs.iter().collect();
+    regs.sort();
+    regs
+}
+
+fn split_lines(text: &[u8]) -> Vec<(&str, Span)> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+
+    for (i, c) in text.iter().enumerate() {
+        if *c == b'\n' {
+            lines.push(start..i);
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(start..text.len());
+    }
+
+    lines.into_iter()
+        .map(|mut range| {
+            // CRLF line endings
+            if text[range.clone()].ends_with(b"\r") {
+                range.end -= 1;
+            }
+            let line = std::str::from_utf8(&text[range.clone()]).unwrap_or("");
+            (line, (range.start, range.end))
+        })
+        .collect()
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, ';') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_identifier(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_')
+}
+
+// Parses `name`, `name()` or `name(arg, (arg, with, commas), ...)`
+fn parse_call(text: &str) -> Option<(&str, Vec<String>)> {
+    let text = text.trim();
+    let name_end = text.find('(').unwrap_or(text.len());
+    let name = text[..name_end].trim();
+
+    if !is_identifier(name) {
+        return None;
+    }
+    if name_end == text.len() {
+        return Some((name, Vec::new()));
+    }
+
+    let inner = text[name_end + 1..].strip_suffix(')')?;
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+
+    for (i, c) in inner.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => {
+                if depth == 0 {
+                    return None;
+                }
+                depth -= 1;
+            }
+            (None, ',') if depth == 0 => {
+                args.push(inner[start..i].to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 || quote.is_some() {
+        return None;
+    }
+    if !inner.trim().is_empty() || !args.is_empty() {
+        args.push(inner[start..].to_string());
+    }
+
+    Some((name, args.iter().map(|arg| unwrap_parens(arg.trim()).to_string()).collect()))
+}
+
+// `(a, b)` -> `a, b`, only when the parentheses enclose the whole argument
+fn unwrap_parens(arg: &str) -> &str {
+    if !arg.starts_with('(') || !arg.ends_with(')') {
+        return arg;
+    }
+
+    let mut depth = 0;
+    for (i, c) in arg.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 && i != arg.len() - 1 {
+                    return arg;
+                }
+            }
+            _ => {}
+        }
+    }
+    arg[1..arg.len() - 1].trim()
+}
+
+// Names used as `${name}` in a line
+fn substitutions(line: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = line;
+
+    while let Some(start) = rest.find("${") {
+        rest = &rest[start + 2..];
+        match rest.find('}') {
+            Some(end) => {
+                names.push(rest[..end].trim());
+                rest = &rest[end + 1..];
+            }
+            None => break,
+        }
+    }
+    names
+}
+
+fn substitute(line: &str, params: &[String], args: &[String], id: &str) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+
+    while let Some(start) = rest.find("${") {
+        result += &rest[..start];
+        rest = &rest[start + 2..];
+
+        let end = rest.find('}').unwrap_or(rest.len());
+        let name = rest[..end].trim();
+
+        if name == "macro_id" {
+            result += id;
+        } else if let Some(i) = params.iter().position(|param| param == name) {
+            result += &args[i];
+        } else {
+            // Left for the function expansion
+            result += &format!("${{{}}}", name);
+        }
+        rest = &rest[(end + 1).min(rest.len())..];
+    }
+    result + rest
+}
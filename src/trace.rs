@@ -0,0 +1,185 @@
+use crate::disassembler::{decode, REGISTER_NAMES};
+use crate::object::{Reader, write_u16};
+use crate::vm::{Inst, MachineState};
+
+// Executed instructions, recorded by `VM::start_trace`.
+//
+// All integers are big-endian.
+//
+// "VMTR" version:u8
+// pc:u16 skip_flag:u8 registers:u16*16 memory_len:u32 memory       state when recording started
+// entry_count:u32 (pc:u16 opcode:u8 operands next_pc:u16 skip_flag:u8
+//                  register_count:u8 (index:u8 value:u16)* write_count:u16 (address:u16 value:u8)*)*
+pub const TRACE_MAGIC: &[u8; 4] = b"VMTR";
+pub const TRACE_VERSION: u8 = 1;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Trace {
+    pub initial: MachineState,
+    pub entries: Vec<TraceEntry>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub inst: Inst,
+    // Bytes after the opcode
+    pub operands: Vec<u8>,
+    // New values of the registers that changed
+    pub registers: Vec<(u8, u16)>,
+    // Bytes written, in order
+    pub memory: Vec<(u16, u8)>,
+    pub skip_flag: bool,
+    pub next_pc: u16,
+}
+
+impl TraceEntry {
+    fn apply(&self, state: &mut MachineState) {
+        for (index, value) in &self.registers {
+            state.registers[*index as usize] = *value;
+        }
+        for (address, value) in &self.memory {
+            state.memory[*address as usize] = *value;
+        }
+        state.skip_flag = self.skip_flag;
+        state.pc = self.next_pc;
+    }
+
+    fn to_json(&self, step: usize) -> String {
+        let mut bytes = vec![self.inst as u8];
+        bytes.extend_from_slice(&self.operands);
+        let text = decode(&bytes, self.pc).map_or_else(|| format!("{:?}", self.inst), |instruction| instruction.to_string());
+
+        let operands: Vec<String> = self.operands.iter().map(|byte| byte.to_string()).collect();
+        let registers: Vec<String> = self.registers.iter()
+            .map(|(index, value)| format!("\"{}\":{}", REGISTER_NAMES[*index as usize], value))
+            .collect();
+        let memory: Vec<String> = self.memory.iter().map(|(address, value)| format!("[{},{}]", address, value)).collect();
+
+        format!(
+            "{{\"step\":{},\"pc\":{},\"inst\":\"{:?}\",\"text\":\"{}\",\"operands\":[{}],\"registers\":{{{}}},\"memory\":[{}],\"skip_flag\":{},\"next_pc\":{}}}",
+            step, self.pc, self.inst, text, operands.join(","), registers.join(","), memory.join(","), self.skip_flag, self.next_pc
+        )
+    }
+}
+
+impl Trace {
+    pub fn new(initial: MachineState) -> Trace {
+        Trace { initial, entries: Vec::new() }
+    }
+
+    // Machine state after the first `step` instructions
+    pub fn state_at(&self, step: usize) -> Result<MachineState, String> {
+        if step > self.entries.len() {
+            return Err(format!("Step {} is past the end of the trace, which has {} steps", step, self.entries.len()));
+        }
+
+        let mut state = self.initial.clone();
+        for entry in &self.entries[..step] {
+            entry.apply(&mut state);
+        }
+        Ok(state)
+    }
+
+    // One object per instruction, for other tools. Only the binary form has the initial state.
+    pub fn to_json_lines(&self) -> String {
+        self.entries.iter().enumerate()
+            .map(|(step, entry)| entry.to_json(step) + "\n")
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = TRACE_MAGIC.to_vec();
+        bytes.push(TRACE_VERSION);
+
+        let initial = &self.initial;
+        write_u16(&mut bytes, initial.pc as usize);
+        bytes.push(initial.skip_flag as u8);
+        for register in &initial.registers {
+            write_u16(&mut bytes, *register as usize);
+        }
+        bytes.extend_from_slice(&(initial.memory.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&initial.memory);
+
+        bytes.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            write_u16(&mut bytes, entry.pc as usize);
+            bytes.push(entry.inst as u8);
+            bytes.extend_from_slice(&entry.operands);
+            write_u16(&mut bytes, entry.next_pc as usize);
+            bytes.push(entry.skip_flag as u8);
+
+            bytes.push(entry.registers.len() as u8);
+            for (index, value) in &entry.registers {
+                bytes.push(*index);
+                write_u16(&mut bytes, *value as usize);
+            }
+            write_u16(&mut bytes, entry.memory.len());
+            for (address, value) in &entry.memory {
+                write_u16(&mut bytes, *address as usize);
+                bytes.push(*value);
+            }
+        }
+        bytes
+    }
+
+    pub fn is_trace(bytes: &[u8]) -> bool {
+        bytes.starts_with(TRACE_MAGIC)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Trace, String> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        if reader.take(4)? != TRACE_MAGIC {
+            return Err("Not a trace".to_string());
+        }
+        let version = reader.u8()?;
+        if version != TRACE_VERSION {
+            return Err(format!("Unsupported trace version {}, expected {}", version, TRACE_VERSION));
+        }
+
+        let pc = reader.u16()?;
+        let skip_flag = reader.u8()? != 0;
+        let mut registers = [0; 16];
+        for register in registers.iter_mut() {
+            *register = reader.u16()?;
+        }
+        let len = reader.u32()? as usize;
+        let memory = reader.take(len)?.to_vec();
+        let initial = MachineState { pc, registers, skip_flag, memory };
+
+        let count = reader.u32()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let pc = reader.u16()?;
+            let opcode = reader.u8()?;
+            let inst = Inst::from_byte(opcode).ok_or_else(|| format!("Invalid opcode 0x{:02X} in trace", opcode))?;
+            let operands = reader.take(inst.len() as usize - 1)?.to_vec();
+            let next_pc = reader.u16()?;
+            let skip_flag = reader.u8()? != 0;
+
+            let mut registers = Vec::new();
+            for _ in 0..reader.u8()? {
+                let index = reader.u8()?;
+                if index as usize >= REGISTER_NAMES.len() {
+                    return Err(format!("Invalid register {} in trace", index));
+                }
+                registers.push((index, reader.u16()?));
+            }
+            let mut memory = Vec::new();
+            for _ in 0..reader.u16()? {
+                let address = reader.u16()?;
+                if address as usize >= initial.memory.len() {
+                    return Err(format!("Write to 0x{:04X} is outside of the traced memory", address));
+                }
+                memory.push((address, reader.u8()?));
+            }
+            entries.push(TraceEntry { pc, inst, operands, registers, memory, skip_flag, next_pc });
+        }
+
+        if reader.pos != bytes.len() {
+            return Err("Trailing bytes after the trace".to_string());
+        }
+        Ok(Trace { initial, entries })
+    }
+}
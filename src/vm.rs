@@ -1,15 +1,145 @@
-use std::intrinsics::transmute;
-use std::ops::Add;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+
+use crate::disassembler::decode_all;
+use crate::image::{Image, SectionKind};
+use crate::trace::{Trace, TraceEntry};
 
 pub struct VM {
     registers: [u16; 16],
-    ram: [u8; 1024],
+    ram: Vec<u8>,
+    pc: u16,
+    // Where `reset` starts execution
+    entry: u16,
+    skip_flag: bool,
+    halted: bool,
+    trap_division_by_zero: bool,
+    // By id, ids are never reused
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watchpoint: usize,
+    // First watched access of the current instruction
+    watch_hit: Option<(usize, MemoryAccess)>,
+    // Recording since `start_trace`
+    trace: Option<Trace>,
+    // Data accesses of the current instruction, only collected for the trace and history
+    accesses: Vec<MemoryAccess>,
+    // Undo records of the last `history_size` instructions, oldest first
+    history: VecDeque<Undo>,
+    history_size: usize,
+    // End of the loaded program, pushes below it are stack overflows
+    stack_limit: usize,
+    // (start, end) of the loaded text sections, the only memory `disassembly` decodes
+    text: Vec<(usize, usize)>,
+}
+
+// What `step_back` needs to undo an instruction
+struct Undo {
     pc: u16,
+    inst: Inst,
+    // Old values of the registers that changed
+    registers: Vec<(u8, u16)>,
     skip_flag: bool,
+    accesses: Vec<MemoryAccess>,
+}
+
+// 16-bit addresses can reach 64 KiB
+pub const MAX_MEMORY_SIZE: usize = 0x10000;
+// Enough for the initial stack slot
+pub const MIN_MEMORY_SIZE: usize = 2;
+pub const DEFAULT_MEMORY_SIZE: usize = 1024;
+// Room images leave for the stack above their sections
+pub const STACK_RESERVE: usize = 256;
+
+// Everything an instruction can change
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MachineState {
+    pub pc: u16,
+    pub registers: [u16; 16],
+    pub skip_flag: bool,
+    pub memory: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VMConfig {
+    memory_size: usize,
+    trap_division_by_zero: bool,
+    history_size: usize,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExitReason {
+    Exit,
+    BudgetExhausted,
+    // Paused after the instruction that hit it
+    Watchpoint(WatchHit),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    Executed(Inst),
+    // The instruction was executed and touched a watched range
+    Watchpoint(WatchHit),
+    Exited,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Watchpoint {
+    pub start: usize,
+    pub len: usize,
+    pub kind: WatchKind,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// Data access by an instruction, `old` and `new` are equal for reads. Fetches aren't accesses.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: usize,
+    // 1 or 2 bytes
+    pub width: usize,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    // Address of the instruction
+    pub pc: u16,
+    pub inst: Inst,
+    pub access: MemoryAccess,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Fault {
+    pub pc: u16,
+    pub kind: FaultKind,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultKind {
+    InvalidOpcode(u8),
+    InvalidRegister(u8),
+    OutOfBounds(usize),
+    StackOverflow,
+    StackUnderflow,
+    DivisionByZero,
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Inst {
     Nop,
     Exit,
@@ -37,11 +167,23 @@ pub enum Inst {
     Call,
     Mov,
     Debug,
+    LoadWord,
+    LoadByte,
+    StoreWord,
+    StoreByte,
+    And,
+    Or,
+    Xor,
+    Not,
+    ShiftLeft,
+    ShiftRight,
+    ShiftRightArith,
+    Jump,
 }
 
-const SP_REGISTER: usize = 15;
+pub const SP_REGISTER: usize = 15;
 const AT_REGISTER: usize = 14;
-const INSTRUCTION_LEN: [u16; 26] = [
+const INSTRUCTION_LEN: [u16; 38] = [
     1, // Nop
     1, // Exit
     2, // JumpFw
@@ -67,252 +209,778 @@ const INSTRUCTION_LEN: [u16; 26] = [
     1, // Return
     3, // Call
     3, // Mov
-    3  // Debug
+    3, // Debug
+    4, // LoadWord
+    4, // LoadByte
+    4, // StoreWord
+    4, // StoreByte
+    3, // And
+    3, // Or
+    3, // Xor
+    2, // Not
+    3, // ShiftLeft
+    3, // ShiftRight
+    3, // ShiftRightArith
+    3  // Jump
+];
+const INSTRUCTIONS: [Inst; 38] = [
+    Inst::Nop, Inst::Exit, Inst::JumpFw, Inst::JumpBw, Inst::Then, Inst::Otherwise,
+    Inst::SetByte, Inst::SetShort, Inst::Push, Inst::Pop, Inst::Add, Inst::Sub,
+    Inst::Mul, Inst::Div, Inst::Mod, Inst::Neg, Inst::GreaterThan, Inst::LessThan,
+    Inst::GreaterEqual, Inst::LessEqual, Inst::Equal, Inst::NotEqual, Inst::Return, Inst::Call,
+    Inst::Mov, Inst::Debug, Inst::LoadWord, Inst::LoadByte, Inst::StoreWord, Inst::StoreByte,
+    Inst::And, Inst::Or, Inst::Xor, Inst::Not, Inst::ShiftLeft, Inst::ShiftRight,
+    Inst::ShiftRightArith, Inst::Jump,
 ];
 
+impl Inst {
+    pub fn from_byte(byte: u8) -> Option<Inst> {
+        INSTRUCTIONS.get(byte as usize).copied()
+    }
+
+    pub fn len(self) -> u16 {
+        INSTRUCTION_LEN[self as usize]
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FaultKind::InvalidOpcode(opcode) => write!(f, "Invalid opcode 0x{:02X}", opcode)?,
+            FaultKind::InvalidRegister(reg) => write!(f, "Invalid register {}", reg)?,
+            FaultKind::OutOfBounds(addr) => write!(f, "Memory access out of bounds at 0x{:04X}", addr)?,
+            FaultKind::StackOverflow => write!(f, "Stack overflow")?,
+            FaultKind::StackUnderflow => write!(f, "Stack underflow")?,
+            FaultKind::DivisionByZero => write!(f, "Division by zero")?,
+        }
+        write!(f, " at pc 0x{:04X}", self.pc)
+    }
+}
+
+impl WatchKind {
+    fn matches(self, access: AccessKind) -> bool {
+        match self {
+            WatchKind::Read => access == AccessKind::Read,
+            WatchKind::Write => access == AccessKind::Write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let MemoryAccess { kind, address, old, new, .. } = self.access;
+        match kind {
+            AccessKind::Read => write!(f, "Watchpoint {}: read 0x{:04X} at 0x{:04X}", self.id, old, address)?,
+            AccessKind::Write => write!(f, "Watchpoint {}: 0x{:04X} -> 0x{:04X} at 0x{:04X}", self.id, old, new, address)?,
+        }
+        write!(f, " by {} at pc 0x{:04X}", self.inst.mnemonic(), self.pc)
+    }
+}
+
+impl VMConfig {
+    pub fn new() -> VMConfig {
+        VMConfig {
+            memory_size: DEFAULT_MEMORY_SIZE,
+            trap_division_by_zero: false,
+            history_size: 0,
+        }
+    }
+
+    pub fn memory_size(mut self, size: usize) -> VMConfig {
+        self.memory_size = size;
+        self
+    }
+
+    pub fn trap_division_by_zero(mut self, trap: bool) -> VMConfig {
+        self.trap_division_by_zero = trap;
+        self
+    }
+
+    // Instructions `step_back` can undo, 0 turns the history off
+    pub fn history_size(mut self, size: usize) -> VMConfig {
+        self.history_size = size;
+        self
+    }
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        VMConfig::new()
+    }
+}
+
 impl VM {
     pub fn new() -> VM {
-        VM {
+        VM::with_config(VMConfig::new()).expect("Default config must be valid")
+    }
+
+    pub fn with_config(config: VMConfig) -> Result<VM, String> {
+        if config.memory_size < MIN_MEMORY_SIZE || config.memory_size > MAX_MEMORY_SIZE {
+            return Err(format!(
+                "Invalid memory size {}, expected {} to {} bytes",
+                config.memory_size, MIN_MEMORY_SIZE, MAX_MEMORY_SIZE
+            ));
+        }
+
+        Ok(VM {
             registers: [0; 16],
-            ram: [0; 1024],
+            ram: vec![0; config.memory_size],
             pc: 0,
+            entry: 0,
             skip_flag: false,
-        }
+            halted: false,
+            trap_division_by_zero: config.trap_division_by_zero,
+            watchpoints: BTreeMap::new(),
+            next_watchpoint: 1,
+            watch_hit: None,
+            trace: None,
+            accesses: Vec::new(),
+            history: VecDeque::new(),
+            history_size: config.history_size,
+            stack_limit: 0,
+            text: Vec::new(),
+        })
+    }
+
+    pub fn entry(&self) -> u16 {
+        self.entry
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.ram.len()
     }
 
     pub fn reset(&mut self) {
-        self.registers[SP_REGISTER] = self.ram.len() as u16 - 2;
-        self.pc = 0;
+        self.registers[SP_REGISTER] = self.stack_top() as u16;
+        self.pc = self.entry;
+        self.halted = false;
+        self.history.clear();
+    }
+
+    // Copies a program to address 0 and resets the machine
+    pub fn load(&mut self, program: &[u8]) -> Result<(), String> {
+        if program.len() > self.ram.len() {
+            return Err(format!(
+                "Program of {} bytes does not fit in {} bytes of memory",
+                program.len(), self.ram.len()
+            ));
+        }
+
+        self.ram[..program.len()].copy_from_slice(program);
+        self.stack_limit = program.len();
+        self.text = vec![(0, program.len())];
+        self.entry = 0;
+        self.reset();
+        Ok(())
+    }
+
+    // Checks the image against this machine, then copies its sections and starts at its entry point
+    pub fn load_image(&mut self, image: &Image) -> Result<(), String> {
+        image.validate()?;
+        if image.memory_size > self.ram.len() {
+            return Err(format!(
+                "Image needs {} bytes of memory but the machine has {}",
+                image.memory_size, self.ram.len()
+            ));
+        }
+        // The first push writes the two bytes at the top of memory
+        if let Some(section) = image.sections.iter().find(|section| section.address as usize + section.size > self.stack_top()) {
+            return Err(format!(
+                "Stack at {:#06X} would overwrite the {:?} section at {:#06X}",
+                self.stack_top(), section.kind, section.address
+            ));
+        }
+
+        for section in &image.sections {
+            let start = section.address as usize;
+            let target = &mut self.ram[start..start + section.size];
+
+            match section.kind {
+                SectionKind::Bss => target.iter_mut().for_each(|byte| *byte = 0),
+                _ => target.copy_from_slice(&section.bytes),
+            }
+        }
+
+        self.entry = image.entry;
+        self.stack_limit = image.sections.iter().map(|section| section.address as usize + section.size).max().unwrap_or(0);
+        self.text = image.sections.iter()
+            .filter(|section| section.kind == SectionKind::Text)
+            .map(|section| (section.address as usize, section.address as usize + section.size))
+            .collect();
+        self.reset();
+        Ok(())
+    }
+
+    // Pauses execution after instructions that access `len` bytes from `start`, returns the watchpoint's id.
+    // The range can't be empty or go past the end of memory.
+    pub fn add_watchpoint(&mut self, start: usize, len: usize, kind: WatchKind) -> Result<usize, String> {
+        if len == 0 {
+            return Err("Watchpoint of 0 bytes".to_string());
+        }
+        if start >= self.ram.len() || len > self.ram.len() - start {
+            return Err(format!(
+                "Watchpoint of {} bytes at 0x{:04X} doesn't fit in {} bytes of memory",
+                len, start, self.ram.len()
+            ));
+        }
+
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.insert(id, Watchpoint { start, len, kind });
+        Ok(id)
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.watchpoints.remove(&id).is_some()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints.iter().map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    // Records every instruction executed from now on, faulting ones excepted
+    pub fn start_trace(&mut self) {
+        self.trace = Some(Trace::new(self.state()));
+    }
+
+    pub fn stop_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    pub fn state(&self) -> MachineState {
+        MachineState {
+            pc: self.pc,
+            registers: self.registers,
+            skip_flag: self.skip_flag,
+            memory: self.ram.clone(),
+        }
     }
 
     pub fn set(&mut self, i: u8) {
         self.ram[self.pc as usize] = i;
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> Result<ExitReason, Fault> {
         loop {
-            let inst = self.ram[self.pc as usize];
-            self.pc += 1;
-            let inst_parsed = unsafe { transmute::<u8, Inst>(inst) };
-//            println!("{:?}", inst_parsed); // DEBUG
-            match inst_parsed {
-                Inst::Nop => {}
-                Inst::Exit => { return; }
-                Inst::JumpFw => {
-                    self.pc += self.ram[self.pc as usize] as u16 + 1;
-                }
-                Inst::JumpBw => {
-                    self.pc = ((self.pc as i16) + (self.ram[self.pc as usize] as i8) as i16 - 1i16) as u16;
-                }
-                Inst::Then => {
-                    if !self.skip_flag {
-                        self.pc += INSTRUCTION_LEN[self.ram[self.pc as usize] as usize];
-                    }
-                }
-                Inst::Otherwise => {
-                    if self.skip_flag {
-                        self.pc += INSTRUCTION_LEN[self.ram[self.pc as usize] as usize];
-                    }
-                }
-                Inst::SetByte => {
-                    let reg = self.ram[self.pc as usize];
-                    let byte = self.ram[(self.pc + 1) as usize] as u16;
-
-                    if reg != 0 {
-                        self.registers[reg as usize] = byte;
-                    }
-                    self.pc += 2;
-                }
-                Inst::SetShort => {
-                    let reg = self.ram[self.pc as usize];
-                    let low_bytes = self.ram[(self.pc + 2) as usize] as u16;
-                    let high_bytes = self.ram[(self.pc + 1) as usize] as u16;
-
-                    if reg != 0 {
-                        self.registers[reg as usize] = (high_bytes << 8) | low_bytes;
-                    }
-                    self.pc += 3;
-                }
-                Inst::Push => {
-                    let sp = self.registers[SP_REGISTER];
-                    self.registers[SP_REGISTER] -= 2;
-
-                    let reg = self.ram[self.pc as usize];
-                    self.ram[(sp + 1) as usize] = self.registers[reg as usize] as u8;
-                    self.ram[sp as usize] = (self.registers[reg as usize] >> 8) as u8;
-                    self.pc += 1;
-                }
-                Inst::Pop => {
-                    self.registers[SP_REGISTER] += 2;
-                    let sp = self.registers[SP_REGISTER];
-
-                    let reg = self.ram[self.pc as usize];
-                    if reg != 0 {
-                        self.registers[reg as usize] = self.ram[(sp + 1) as usize] as u16;
-                        self.registers[reg as usize] |= (self.ram[sp as usize] as u16) << 8;
-                    }
-                    self.pc += 1;
-                }
-                Inst::Add => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
-
-                    if a != 0 {
-                        let x = self.registers[a as usize] as i16;
-                        let y = self.registers[b as usize] as i16;
-                        self.registers[a as usize] = x.wrapping_add(y) as u16;
-                    }
-                    self.pc += 2;
-                }
-                Inst::Sub => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
-
-                    if a != 0 {
-                        let x = self.registers[a as usize] as i16;
-                        let y = self.registers[b as usize] as i16;
-                        self.registers[a as usize] = x.wrapping_sub(y) as u16;
-                        ;
-                    }
-                    self.pc += 2;
-                }
-                Inst::Mul => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
-
-                    if a != 0 {
-                        self.registers[a as usize] = ((self.registers[a as usize] as i16) * (self.registers[b as usize] as i16)) as u16;
-                    }
-                    self.pc += 2;
-                }
-                Inst::Div => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
-
-                    if self.registers[b as usize] != 0 && a != 0 {
-                        self.registers[a as usize] = ((self.registers[a as usize] as i16) / (self.registers[b as usize] as i16)) as u16;
-                    }
-                    self.pc += 2;
-                }
-                Inst::Mod => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
-
-                    if self.registers[b as usize] != 0 && a != 0 {
-                        self.registers[a as usize] = ((self.registers[a as usize] as i16) % (self.registers[b as usize] as i16)) as u16;
-                    }
-                    self.pc += 2;
-                }
-                Inst::Neg => {
-                    let reg = self.ram[self.pc as usize];
-                    if reg != 0 {
-                        self.registers[reg as usize] = (-(self.registers[reg as usize] as i16)) as u16;
-                    }
-                    self.pc += 1;
-                }
-                Inst::GreaterThan => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
+            match self.step()? {
+                Step::Executed(_) => {}
+                Step::Watchpoint(hit) => return Ok(ExitReason::Watchpoint(hit)),
+                Step::Exited => return Ok(ExitReason::Exit),
+            }
+        }
+    }
 
-                    self.skip_flag = (self.registers[a as usize] as i16) > (self.registers[b as usize] as i16);
-                    self.pc += 2;
-                }
-                Inst::LessThan => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
+    // Executes at most `budget` instructions, so untrusted programs always give control back
+    pub fn run_for(&mut self, budget: u64) -> Result<ExitReason, Fault> {
+        for _ in 0..budget {
+            match self.step()? {
+                Step::Executed(_) => {}
+                Step::Watchpoint(hit) => return Ok(ExitReason::Watchpoint(hit)),
+                Step::Exited => return Ok(ExitReason::Exit),
+            }
+        }
+        Ok(ExitReason::BudgetExhausted)
+    }
 
-                    self.skip_flag = (self.registers[a as usize] as i16) < (self.registers[b as usize] as i16);
-                    self.pc += 2;
-                }
-                Inst::GreaterEqual => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
+    // Executes a single instruction, on a fault pc is left pointing at the faulting instruction
+    pub fn step(&mut self) -> Result<Step, Fault> {
+        if self.halted {
+            return Ok(Step::Exited);
+        }
+        let pc = self.pc;
+        let registers = self.registers;
+        let skip_flag = self.skip_flag;
+        self.watch_hit = None;
+        self.accesses.clear();
+
+        let result = self.execute();
+        if let Ok(inst) = result {
+            self.record(pc, inst, registers);
+            self.remember(pc, inst, registers, skip_flag);
+        }
 
-                    self.skip_flag = (self.registers[a as usize] as i16) >= (self.registers[b as usize] as i16);
-                    self.pc += 2;
-                }
-                Inst::LessEqual => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
+        match result {
+            Ok(Inst::Exit) => {
+                self.halted = true;
+                Ok(Step::Exited)
+            }
+            Ok(inst) => match self.watch_hit.take() {
+                Some((id, access)) => Ok(Step::Watchpoint(WatchHit { id, pc, inst, access })),
+                None => Ok(Step::Executed(inst)),
+            },
+            Err(kind) => {
+                self.pc = pc;
+                Err(Fault { pc, kind })
+            }
+        }
+    }
 
-                    self.skip_flag = (self.registers[a as usize] as i16) <= (self.registers[b as usize] as i16);
-                    self.pc += 2;
-                }
-                Inst::Equal => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
+    // Adds the instruction at `pc` to the trace, `registers` are the values before it ran
+    fn record(&mut self, pc: u16, inst: Inst, registers: [u16; 16]) {
+        if self.trace.is_none() {
+            return;
+        }
 
-                    self.skip_flag = self.registers[a as usize] == self.registers[b as usize];
-                    self.pc += 2;
-                }
-                Inst::NotEqual => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
+        let operands = (1..inst.len()).map(|i| self.ram[pc.wrapping_add(i) as usize]).collect();
+        let changed = (0..registers.len())
+            .filter(|i| registers[*i] != self.registers[*i])
+            .map(|i| (i as u8, self.registers[i]))
+            .collect();
+        let mut memory = Vec::new();
+        for write in self.accesses.iter().filter(|access| access.kind == AccessKind::Write) {
+            if write.width == 2 {
+                memory.push((write.address as u16, (write.new >> 8) as u8));
+                memory.push((write.address as u16 + 1, write.new as u8));
+            } else {
+                memory.push((write.address as u16, write.new as u8));
+            }
+        }
 
-                    self.skip_flag = self.registers[a as usize] != self.registers[b as usize];
-                    self.pc += 2;
-                }
-                Inst::Return => {
-                    self.registers[SP_REGISTER] += 2;
-                    let sp = self.registers[SP_REGISTER];
+        let entry = TraceEntry {
+            pc,
+            inst,
+            operands,
+            registers: changed,
+            memory,
+            skip_flag: self.skip_flag,
+            next_pc: self.pc,
+        };
+        if let Some(trace) = &mut self.trace {
+            trace.entries.push(entry);
+        }
+    }
 
-                    self.registers[AT_REGISTER] = self.ram[(sp + 1) as usize] as u16;
-                    self.registers[AT_REGISTER] |= (self.ram[sp as usize] as u16) << 8;
+    // Adds an undo record for the instruction at `pc`, the arguments are the values before it ran
+    fn remember(&mut self, pc: u16, inst: Inst, registers: [u16; 16], skip_flag: bool) {
+        if self.history_size == 0 {
+            return;
+        }
+        if self.history.len() == self.history_size {
+            self.history.pop_front();
+        }
 
-                    self.pc = self.registers[AT_REGISTER];
-                }
-                Inst::Call => {
-                    let low_bytes = self.ram[(self.pc + 1) as usize] as u16;
-                    let high_bytes = self.ram[(self.pc) as usize] as u16;
-                    self.pc += 2;
+        let changed = (0..registers.len())
+            .filter(|i| registers[*i] != self.registers[*i])
+            .map(|i| (i as u8, registers[i]))
+            .collect();
+        self.history.push_back(Undo { pc, inst, registers: changed, skip_flag, accesses: self.accesses.clone() });
+    }
 
-                    // Store return addr
-                    let sp = self.registers[SP_REGISTER];
-                    self.registers[SP_REGISTER] -= 2;
+    // Undoes the last instruction, None once the history is used up. Returns a watchpoint hit
+    // when the undone instruction touched a watched range.
+    pub fn step_back(&mut self) -> Option<Step> {
+        let undo = self.history.pop_back()?;
+
+        // Newest first, so overlapping writes end up with the oldest value
+        for access in undo.accesses.iter().rev().filter(|access| access.kind == AccessKind::Write) {
+            if access.width == 2 {
+                self.ram[access.address] = (access.old >> 8) as u8;
+                self.ram[access.address + 1] = access.old as u8;
+            } else {
+                self.ram[access.address] = access.old as u8;
+            }
+        }
+        for (index, value) in &undo.registers {
+            self.registers[*index as usize] = *value;
+        }
+        self.pc = undo.pc;
+        self.skip_flag = undo.skip_flag;
+        self.halted = false;
+
+        let hit = undo.accesses.iter().find_map(|access| self.watched(access).map(|id| (id, *access)));
+        match hit {
+            Some((id, access)) => Some(Step::Watchpoint(WatchHit { id, pc: undo.pc, inst: undo.inst, access })),
+            None => Some(Step::Executed(undo.inst)),
+        }
+    }
 
-                    self.ram[(sp + 1) as usize] = self.pc as u8;
-                    self.ram[sp as usize] = (self.pc >> 8) as u8;
+    // Steps back to the last instruction that touched a watched range, None if the history
+    // runs out first. Answers "who wrote this value" with a write watchpoint.
+    pub fn reverse_continue(&mut self) -> Option<WatchHit> {
+        loop {
+            if let Step::Watchpoint(hit) = self.step_back()? {
+                return Some(hit);
+            }
+        }
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
 
-                    // Jump to subroutine
-                    self.pc = (high_bytes << 8) | low_bytes;
+    fn execute(&mut self) -> Result<Inst, FaultKind> {
+        let opcode = self.fetch()?;
+        let inst = Inst::from_byte(opcode).ok_or(FaultKind::InvalidOpcode(opcode))?;
+        match inst {
+            Inst::Nop => {}
+            Inst::Exit => {}
+            // Relative jumps count from the end of the instruction
+            Inst::JumpFw => {
+                let offset = self.fetch()? as u16;
+                self.pc = self.pc.wrapping_add(offset);
+            }
+            Inst::JumpBw => {
+                let offset = self.fetch()? as u16;
+                self.pc = self.pc.wrapping_sub(offset);
+            }
+            Inst::Then => {
+                if !self.skip_flag {
+                    self.skip_next()?;
                 }
-                Inst::Mov => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
-
-                    if a != 0 {
-                        self.registers[a as usize] = self.registers[b as usize];
-                    }
-                    self.pc += 2;
+            }
+            Inst::Otherwise => {
+                if self.skip_flag {
+                    self.skip_next()?;
                 }
-                Inst::Debug => {
-                    let a = self.ram[self.pc as usize];
-                    let b = self.ram[(self.pc + 1) as usize];
-
-                    match b {
-                        0 => println!("{}", self.registers[a as usize]),
-                        1 => println!("{:x}", self.registers[a as usize]),
-                        2 => println!("{:05}", self.registers[a as usize]),
-                        3 => println!("{:04X}", self.registers[a as usize]),
-                        4 => println!("0x{:04X}", self.registers[a as usize]),
-                        10 => print!("{}", self.registers[a as usize]),
-                        11 => print!("{:x}", self.registers[a as usize]),
-                        12 => print!("{:05}", self.registers[a as usize]),
-                        13 => print!("{:04X}", self.registers[a as usize]),
-                        14 => print!("0x{:04X}", self.registers[a as usize]),
-                        _ => self.print(),
-                    }
-
-                    self.pc += 2;
+            }
+            Inst::SetByte => {
+                let reg = self.fetch()?;
+                let byte = self.fetch()? as u16;
+
+                self.set_reg(reg, byte)?;
+            }
+            Inst::SetShort => {
+                let reg = self.fetch()?;
+                let high_bytes = self.fetch()? as u16;
+                let low_bytes = self.fetch()? as u16;
+
+                self.set_reg(reg, (high_bytes << 8) | low_bytes)?;
+            }
+            Inst::Push => {
+                let reg = self.fetch()?;
+                let value = self.reg(reg)?;
+
+                self.push(value)?;
+            }
+            Inst::Pop => {
+                let reg = self.fetch()?;
+                let value = self.pop()?;
+
+                self.set_reg(reg, value)?;
+            }
+            Inst::Add => {
+                let (a, x, y) = self.fetch_2reg()?;
+                self.set_reg(a, (x as i16).wrapping_add(y as i16) as u16)?;
+            }
+            Inst::Sub => {
+                let (a, x, y) = self.fetch_2reg()?;
+                self.set_reg(a, (x as i16).wrapping_sub(y as i16) as u16)?;
+            }
+            Inst::Mul => {
+                let (a, x, y) = self.fetch_2reg()?;
+                self.set_reg(a, (x as i16).wrapping_mul(y as i16) as u16)?;
+            }
+            Inst::Div => {
+                let (a, x, y) = self.fetch_2reg()?;
+
+                if y != 0 {
+                    self.set_reg(a, (x as i16).wrapping_div(y as i16) as u16)?;
+                } else if self.trap_division_by_zero {
+                    return Err(FaultKind::DivisionByZero);
                 }
-                _ => {
-                    panic!("Invalid instruction: {}", inst);
+            }
+            Inst::Mod => {
+                let (a, x, y) = self.fetch_2reg()?;
+
+                if y != 0 {
+                    self.set_reg(a, (x as i16).wrapping_rem(y as i16) as u16)?;
+                } else if self.trap_division_by_zero {
+                    return Err(FaultKind::DivisionByZero);
+                }
+            }
+            Inst::Neg => {
+                let reg = self.fetch()?;
+                let x = self.reg(reg)?;
+                self.set_reg(reg, (x as i16).wrapping_neg() as u16)?;
+            }
+            Inst::GreaterThan => {
+                let (_, x, y) = self.fetch_2reg()?;
+                self.skip_flag = (x as i16) > (y as i16);
+            }
+            Inst::LessThan => {
+                let (_, x, y) = self.fetch_2reg()?;
+                self.skip_flag = (x as i16) < (y as i16);
+            }
+            Inst::GreaterEqual => {
+                let (_, x, y) = self.fetch_2reg()?;
+                self.skip_flag = (x as i16) >= (y as i16);
+            }
+            Inst::LessEqual => {
+                let (_, x, y) = self.fetch_2reg()?;
+                self.skip_flag = (x as i16) <= (y as i16);
+            }
+            Inst::Equal => {
+                let (_, x, y) = self.fetch_2reg()?;
+                self.skip_flag = x == y;
+            }
+            Inst::NotEqual => {
+                let (_, x, y) = self.fetch_2reg()?;
+                self.skip_flag = x != y;
+            }
+            Inst::Return => {
+                let addr = self.pop()?;
+
+                self.registers[AT_REGISTER] = addr;
+                self.pc = addr;
+            }
+            Inst::Call => {
+                let high_bytes = self.fetch()? as u16;
+                let low_bytes = self.fetch()? as u16;
+
+                // Store return addr
+                self.push(self.pc)?;
+
+                // Jump to subroutine
+                self.pc = (high_bytes << 8) | low_bytes;
+            }
+            Inst::Jump => {
+                let high_bytes = self.fetch()? as u16;
+                let low_bytes = self.fetch()? as u16;
+
+                self.pc = (high_bytes << 8) | low_bytes;
+            }
+            Inst::Mov => {
+                let (a, _, y) = self.fetch_2reg()?;
+                self.set_reg(a, y)?;
+            }
+            Inst::Debug => {
+                let a = self.fetch()?;
+                let b = self.fetch()?;
+                let value = self.reg(a)?;
+
+                match b {
+                    0 => println!("{}", value),
+                    1 => println!("{:x}", value),
+                    2 => println!("{:05}", value),
+                    3 => println!("{:04X}", value),
+                    4 => println!("0x{:04X}", value),
+                    10 => print!("{}", value),
+                    11 => print!("{:x}", value),
+                    12 => print!("{:05}", value),
+                    13 => print!("{:04X}", value),
+                    14 => print!("0x{:04X}", value),
+                    _ => self.print(),
                 }
             }
+            Inst::LoadWord => {
+                let (reg, addr) = self.fetch_mem()?;
+                let value = self.read_u16(addr)?;
+                self.set_reg(reg, value)?;
+            }
+            Inst::LoadByte => {
+                let (reg, addr) = self.fetch_mem()?;
+                let value = self.read_u8(addr)? as u16;
+                self.set_reg(reg, value)?;
+            }
+            Inst::StoreWord => {
+                let (reg, addr) = self.fetch_mem()?;
+                let value = self.reg(reg)?;
+                self.write_u16(addr, value)?;
+            }
+            Inst::StoreByte => {
+                let (reg, addr) = self.fetch_mem()?;
+                let value = self.reg(reg)?;
+                self.write_u8(addr, value as u8)?;
+            }
+            Inst::And => {
+                let (a, x, y) = self.fetch_2reg()?;
+                self.set_reg(a, x & y)?;
+            }
+            Inst::Or => {
+                let (a, x, y) = self.fetch_2reg()?;
+                self.set_reg(a, x | y)?;
+            }
+            Inst::Xor => {
+                let (a, x, y) = self.fetch_2reg()?;
+                self.set_reg(a, x ^ y)?;
+            }
+            Inst::Not => {
+                let reg = self.fetch()?;
+                let x = self.reg(reg)?;
+                self.set_reg(reg, !x)?;
+            }
+            Inst::ShiftLeft => {
+                let (a, x, y) = self.fetch_2reg()?;
+                self.set_reg(a, x.checked_shl(y as u32).unwrap_or(0))?;
+            }
+            Inst::ShiftRight => {
+                let (a, x, y) = self.fetch_2reg()?;
+                self.set_reg(a, x.checked_shr(y as u32).unwrap_or(0))?;
+            }
+            Inst::ShiftRightArith => {
+                let (a, x, y) = self.fetch_2reg()?;
+                // Shifting by 15 or more leaves only copies of the sign bit
+                self.set_reg(a, ((x as i16) >> y.min(15)) as u16)?;
+            }
+        }
+        Ok(inst)
+    }
+
+    fn fetch(&mut self) -> Result<u8, FaultKind> {
+        let byte = self.byte(self.pc as usize)?;
+        self.pc = self.pc.wrapping_add(1);
+        Ok(byte)
+    }
+
+    // Reads `dst, src` operands, returns the dst register index and both register values
+    fn fetch_2reg(&mut self) -> Result<(u8, u16, u16), FaultKind> {
+        let a = self.fetch()?;
+        let b = self.fetch()?;
+
+        Ok((a, self.reg(a)?, self.reg(b)?))
+    }
+
+    // Reads `reg, base, offset` operands, returns the register and the effective address
+    fn fetch_mem(&mut self) -> Result<(u8, usize), FaultKind> {
+        let reg = self.fetch()?;
+        let base = self.fetch()?;
+        let offset = self.fetch()? as u16;
+
+        Ok((reg, self.reg(base)?.wrapping_add(offset) as usize))
+    }
+
+    fn skip_next(&mut self) -> Result<(), FaultKind> {
+        let opcode = self.byte(self.pc as usize)?;
+        let len = INSTRUCTION_LEN.get(opcode as usize).ok_or(FaultKind::InvalidOpcode(opcode))?;
+
+        self.pc = self.pc.wrapping_add(*len);
+        Ok(())
+    }
+
+    fn reg(&self, reg: u8) -> Result<u16, FaultKind> {
+        self.registers.get(reg as usize).copied().ok_or(FaultKind::InvalidRegister(reg))
+    }
+
+    // Writes to the zero register are ignored
+    fn set_reg(&mut self, reg: u8, value: u16) -> Result<(), FaultKind> {
+        if reg as usize >= self.registers.len() {
+            return Err(FaultKind::InvalidRegister(reg));
+        }
+        if reg != 0 {
+            self.registers[reg as usize] = value;
+        }
+        Ok(())
+    }
+
+    // Without watchpoint checks, for instruction fetches
+    fn byte(&self, addr: usize) -> Result<u8, FaultKind> {
+        self.ram.get(addr).copied().ok_or(FaultKind::OutOfBounds(addr))
+    }
+
+    // Words are stored big-endian
+    fn word(&self, addr: usize) -> Result<u16, FaultKind> {
+        let high_bytes = self.byte(addr)? as u16;
+        let low_bytes = self.byte(addr + 1)? as u16;
+
+        Ok((high_bytes << 8) | low_bytes)
+    }
+
+    fn read_u8(&mut self, addr: usize) -> Result<u8, FaultKind> {
+        let value = self.byte(addr)?;
+        self.access(AccessKind::Read, addr, 1, value as u16, value as u16);
+        Ok(value)
+    }
+
+    fn write_u8(&mut self, addr: usize, value: u8) -> Result<(), FaultKind> {
+        let old = self.byte(addr)?;
+        self.ram[addr] = value;
+        self.access(AccessKind::Write, addr, 1, old as u16, value as u16);
+        Ok(())
+    }
+
+    fn read_u16(&mut self, addr: usize) -> Result<u16, FaultKind> {
+        let value = self.word(addr)?;
+        self.access(AccessKind::Read, addr, 2, value, value);
+        Ok(value)
+    }
+
+    fn write_u16(&mut self, addr: usize, value: u16) -> Result<(), FaultKind> {
+        // Reading both bytes first means a faulting store leaves memory untouched
+        let old = self.word(addr)?;
+        self.ram[addr] = (value >> 8) as u8;
+        self.ram[addr + 1] = value as u8;
+        self.access(AccessKind::Write, addr, 2, old, value);
+        Ok(())
+    }
+
+    // Every data access goes through here
+    fn access(&mut self, kind: AccessKind, address: usize, width: usize, old: u16, new: u16) {
+        let access = MemoryAccess { kind, address, width, old, new };
+        if self.trace.is_some() || self.history_size > 0 {
+            self.accesses.push(access);
+        }
+        if self.watch_hit.is_none() {
+            self.watch_hit = self.watched(&access).map(|id| (id, access));
+        }
+    }
+
+    // Id of the first watchpoint the access triggers
+    fn watched(&self, access: &MemoryAccess) -> Option<usize> {
+        self.watchpoints.iter()
+            .find(|(_, watchpoint)| {
+                watchpoint.kind.matches(access.kind)
+                    && access.address < watchpoint.start + watchpoint.len
+                    && watchpoint.start < access.address + access.width
+            })
+            .map(|(id, _)| *id)
+    }
+
+    fn push(&mut self, value: u16) -> Result<(), FaultKind> {
+        let sp = self.registers[SP_REGISTER];
+        if (sp as usize) < self.stack_limit {
+            return Err(FaultKind::StackOverflow);
+        }
+        let new_sp = sp.checked_sub(2).ok_or(FaultKind::StackOverflow)?;
+
+        self.write_u16(sp as usize, value)?;
+        self.registers[SP_REGISTER] = new_sp;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u16, FaultKind> {
+        let sp = self.registers[SP_REGISTER] as usize + 2;
+        if sp > self.stack_top() {
+            return Err(FaultKind::StackUnderflow);
+        }
+
+        let value = self.read_u16(sp)?;
+        self.registers[SP_REGISTER] = sp as u16;
+        Ok(value)
+    }
+
+    fn stack_top(&self) -> usize {
+        self.ram.len() - 2
+    }
+
+    pub fn register(&self, index: usize) -> u16 {
+        self.registers[index]
+    }
+
+    // Like a `set` instruction, writes to the zero register are ignored
+    pub fn set_register(&mut self, index: usize, value: u16) {
+        if index != 0 {
+            self.registers[index] = value;
         }
     }
 
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.ram
+    }
+
     pub fn print(&self) {
         print!("{{");
         print!("\n  pc: {}", self.pc);
@@ -328,23 +996,10 @@ impl VM {
     }
 
     pub fn disassembly(&self) {
-        let mut pos = 0;
-
-        while pos < self.ram.len() {
-            let byte = self.ram[pos];
-            let inst = unsafe { transmute::<u8, Inst>(byte) };
-            let len = INSTRUCTION_LEN[byte as usize];
-
-            print!("{:04X}  {:?}", pos, inst);
-            for i in 1..(len as usize) {
-                if pos + i >= self.ram.len() {
-                    continue;
-                }
-                print!(" {:02X}", self.ram[pos + i]);
+        for &(start, end) in &self.text {
+            for line in decode_all(&self.ram[start..end], start as u16) {
+                println!("{:04X}  {}", line.address(), line);
             }
-            println!();
-
-            pos += len as usize;
         }
     }
-}
\ No newline at end of file
+}